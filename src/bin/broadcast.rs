@@ -1,17 +1,26 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 
 use compact_str::CompactString;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tinyset::SetU32;
+use tokio::sync::broadcast::{self, error::RecvError};
 
-use tracing::{debug_span, instrument, Instrument};
+use tracing::{debug, instrument};
 use vortex::{
     error::{JsonDeError, NodeError},
     init_tracing, main_loop,
+    membership::{Event, Membership, Status, Swim},
     message::Message,
     node::Node,
+    plumtree::{Gossip, Plumtree},
+    ranges::{covers, digest},
+    topology::Topology,
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,10 +30,21 @@ pub enum Request {
         message: u32,
     },
     BroadcastOk,
+    /// Also acknowledges the given rounds of the receiver's own batches.
     BroadcastBatch {
         messages: SetU32,
+        round: u64,
+        acks: Vec<u64>,
+    },
+    BroadcastBatchOk {
+        rounds: Vec<u64>,
+    },
+    Digest {
+        ranges: Vec<(u32, u32)>,
+    },
+    DigestOk {
+        messages: SetU32,
     },
-    BroadcastBatchOk,
     Read,
     Topology {
         topology: HashMap<CompactString, Vec<CompactString>>,
@@ -39,49 +59,199 @@ pub enum Response {
 }
 
 const BATCH_PERIOD: Duration = Duration::from_millis(500);
+/// Rounds a batch waits for its acknowledgement before it is written off.
+/// Its values are still pending, so later batches carry them anyway.
+const UNACKED_ROUNDS: u64 = 4;
+const ANTI_ENTROPY_PERIOD: Duration = Duration::from_secs(1);
+const MODE_ENV: &str = "VORTEX_BROADCAST";
+const ANTI_ENTROPY_ENV: &str = "VORTEX_ANTI_ENTROPY";
+
+/// How new values reach other nodes, chosen with `VORTEX_BROADCAST`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Periodic batches of pending values to every peer.
+    Batch,
+    /// Immediate push along a self-repairing spanning tree.
+    Plumtree,
+}
+
+impl Mode {
+    fn from_env() -> Result<Self, NodeError> {
+        match std::env::var(MODE_ENV).as_deref() {
+            Err(_) | Ok("batch") => Ok(Mode::Batch),
+            Ok("plumtree") => Ok(Mode::Plumtree),
+            Ok(other) => Err(NodeError::new(format!("Unknown broadcast mode: {other}"))),
+        }
+    }
+}
+
+/// Whether batch mode also exchanges digests, chosen with
+/// `VORTEX_ANTI_ENTROPY`. Batches are resent until acknowledged, so digests
+/// only speed up recovery from partitions, at a digest and a reply per node
+/// every `ANTI_ENTROPY_PERIOD`. They are off by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AntiEntropy {
+    Off,
+    Digest,
+}
+
+impl AntiEntropy {
+    fn from_env() -> Result<Self, NodeError> {
+        match std::env::var(ANTI_ENTROPY_ENV).as_deref() {
+            Err(_) | Ok("off") => Ok(AntiEntropy::Off),
+            Ok("digest") => Ok(AntiEntropy::Digest),
+            Ok(other) => Err(NodeError::new(format!("Unknown anti-entropy: {other}"))),
+        }
+    }
+}
+
+/// Values still to be delivered to one peer. A value stays pending until the
+/// peer acknowledges a batch holding it, and `unacked` keeps the batches sent
+/// in recent rounds so a late acknowledgement still counts. `known` holds
+/// what the peer has acknowledged or sent us, which never needs to be sent to
+/// it again. `acks` are the peer's rounds received since the last message to
+/// it, acknowledged together with the next one.
+#[derive(Debug, Default)]
+struct Outbox {
+    pending: SetU32,
+    known: SetU32,
+    unacked: BTreeMap<u64, SetU32>,
+    acks: Vec<u64>,
+}
+
+impl Outbox {
+    fn acked(&mut self, rounds: &[u64]) {
+        for round in rounds {
+            if let Some(batch) = self.unacked.remove(round) {
+                self.pending = &self.pending - &batch;
+                self.known = &batch | &self.known;
+            }
+        }
+    }
+}
+
+type Outboxes = Arc<RwLock<HashMap<CompactString, Outbox>>>;
+
+/// Queues newly learned values for every peer that does not have them yet.
+fn enqueue(outboxes: &Outboxes, new: &SetU32) {
+    for outbox in outboxes.write().values_mut() {
+        let unknown = new - &outbox.known;
+        outbox.pending = &unknown | &outbox.pending;
+    }
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> miette::Result<()> {
     init_tracing()?;
+    let strategy = Topology::from_env()?;
+    let mode = Mode::from_env()?;
+    let digests = mode == Mode::Batch && AntiEntropy::from_env()? == AntiEntropy::Digest;
+    let swim = Membership::enabled()?;
 
-    let peers = Arc::new(RwLock::new(vec![]));
-    let messages = Arc::new(RwLock::new(SetU32::new()));
-    let buffer = Arc::new(RwLock::new(SetU32::new()));
+    main_loop(|node| {
+        let outboxes = Outboxes::default();
+        let messages = Arc::new(RwLock::new(SetU32::new()));
+        let plumtree = (mode == Mode::Plumtree).then(|| {
+            let m = messages.clone();
+            let plumtree = Plumtree::new(node.clone(), move |id, _| {
+                m.write().insert(id as u32);
+            });
+            tokio::spawn(plumtree.clone().run());
+            plumtree
+        });
 
-    let p = peers.clone();
-    let b = buffer.clone();
-    let main = main_loop(move |msg, node| handle_msg(msg, node, peers.clone(), messages, buffer))?;
-    tokio::spawn(handle_batch_sending(p, b, main.node.clone()));
-    main.await
+        let membership = (swim && digests).then(|| {
+            let membership = Membership::new(node.clone());
+            tokio::spawn(membership.clone().run());
+            membership
+        });
+        if plumtree.is_none() {
+            tokio::spawn(handle_batch_sending(outboxes.clone(), node.clone()));
+        }
+        if digests {
+            tokio::spawn(handle_anti_entropy(
+                messages.clone(),
+                membership.clone(),
+                node,
+            ));
+        }
+        move |msg, node| {
+            handle_msg(
+                msg, node, strategy, plumtree, membership, outboxes, messages,
+            )
+        }
+    })
+    .await
 }
 
 async fn handle_msg(
     msg: Message<Value>,
     node: Arc<Node>,
-    peers: Arc<RwLock<Vec<CompactString>>>,
+    strategy: Topology,
+    plumtree: Option<Arc<Plumtree>>,
+    membership: Option<Arc<Membership>>,
+    outboxes: Outboxes,
     messages: Arc<RwLock<SetU32>>,
-    buffer: Arc<RwLock<SetU32>>,
 ) -> Result<(), NodeError> {
+    if let Some(plumtree) = plumtree.as_ref().filter(|_| Gossip::matches(&msg)) {
+        return plumtree.handle(&msg).await;
+    }
+    if let Some(membership) = membership.as_ref().filter(|_| Swim::matches(&msg)) {
+        return membership.handle(&msg).await;
+    }
     match Request::de(&msg.body.payload)? {
-        Request::Broadcast { message } => handle_broadcast(&buffer, message, &node, &msg).await,
+        Request::Broadcast { message } => match &plumtree {
+            Some(plumtree) => {
+                plumtree.broadcast(message.into(), Value::Null);
+                node.reply(&msg, Request::BroadcastOk).await
+            }
+            None => handle_broadcast(&messages, &outboxes, message, &node, &msg).await,
+        },
         Request::BroadcastOk => handle_broadcast_ok(&node, &msg),
-        Request::BroadcastBatch { messages: batch } => {
-            handle_broadcast_batch(&messages, &batch, buffer, &node, &msg).await
+        Request::BroadcastBatch {
+            messages: batch,
+            round,
+            ref acks,
+        } => handle_broadcast_batch(&messages, &batch, round, acks, &outboxes, &node, &msg).await,
+        Request::BroadcastBatchOk { ref rounds } => {
+            if let Some(outbox) = outboxes.write().get_mut(&msg.src) {
+                outbox.acked(rounds);
+            }
+            Ok(())
+        }
+        Request::Digest { ref ranges } => {
+            handle_digest(&messages, ranges, &outboxes, &node, &msg).await
+        }
+        Request::DigestOk { messages: missing } => {
+            repair(&messages, &missing, &outboxes);
+            Ok(())
         }
-        Request::BroadcastBatchOk => handle_broadcast_batch_ok(&node, &msg),
         Request::Read => handle_read(messages, &node, &msg).await,
-        Request::Topology { ref topology } => handle_topology(topology, &node, &peers, &msg).await,
+        Request::Topology { ref topology } => {
+            handle_topology(
+                topology,
+                strategy,
+                plumtree.as_deref(),
+                &node,
+                &outboxes,
+                &msg,
+            )
+            .await
+        }
     }
 }
 
 #[instrument("Broadcast", skip_all, fields(message, node))]
 async fn handle_broadcast(
-    buffer: &Arc<RwLock<SetU32>>,
+    messages: &Arc<RwLock<SetU32>>,
+    outboxes: &Outboxes,
     message: u32,
     node: &Arc<Node>,
     msg: &Message<Value>,
 ) -> Result<(), NodeError> {
-    buffer.write().insert(message);
+    if messages.write().insert(message) {
+        enqueue(outboxes, &SetU32::from_iter([message]));
+    }
     node.reply(msg, Request::BroadcastOk).await
 }
 
@@ -94,22 +264,79 @@ fn handle_broadcast_ok(node: &Arc<Node>, msg: &Message<Value>) -> Result<(), Nod
 async fn handle_broadcast_batch(
     messages: &Arc<RwLock<SetU32>>,
     batch: &SetU32,
-    buffer: Arc<RwLock<SetU32>>,
+    round: u64,
+    acks: &[u64],
+    outboxes: &Outboxes,
     node: &Arc<Node>,
     msg: &Message<Value>,
 ) -> Result<(), NodeError> {
-    {
+    // A sender outside this node's topology gets no batches back to carry the
+    // acknowledgement, so it is answered straight away.
+    let neighbour = match outboxes.write().get_mut(&msg.src) {
+        Some(outbox) => {
+            outbox.acked(acks);
+            outbox.known = batch | &outbox.known;
+            outbox.pending = &outbox.pending - batch;
+            outbox.acks.push(round);
+            true
+        }
+        None => false,
+    };
+    let new = {
         let mut mm = messages.write();
-        *mm = batch | &mm;
-        let mut buf = buffer.write();
-        *buf = batch | &buf;
+        let new = batch - &mm;
+        *mm = &new | &mm;
+        new
+    };
+    if !new.is_empty() {
+        enqueue(outboxes, &new);
+    }
+    if neighbour {
+        return Ok(());
     }
-    node.reply(msg, Request::BroadcastBatchOk).await
+    let rounds = vec![round];
+    node.reply(msg, Request::BroadcastBatchOk { rounds }).await
 }
 
-#[instrument("Broadcast Batch Ok", skip(msg))]
-fn handle_broadcast_batch_ok(node: &Arc<Node>, msg: &Message<Value>) -> Result<(), NodeError> {
-    node.ack(msg, Ok(json!(null)))
+/// The ranges are the sender's values themselves, so the digest repairs
+/// this node as well as the sender.
+#[instrument("Digest", skip_all, fields(node))]
+async fn handle_digest(
+    messages: &Arc<RwLock<SetU32>>,
+    ranges: &[(u32, u32)],
+    outboxes: &Outboxes,
+    node: &Arc<Node>,
+    msg: &Message<Value>,
+) -> Result<(), NodeError> {
+    let theirs = ranges
+        .iter()
+        .flat_map(|&(start, end)| start..=end)
+        .collect();
+    let missing = messages
+        .read()
+        .iter()
+        .filter(|m| !covers(ranges, *m))
+        .collect();
+    repair(messages, &theirs, outboxes);
+    node.reply(msg, Request::DigestOk { messages: missing })
+        .await
+}
+
+/// Merges values learned through anti-entropy. They are passed on like newly
+/// broadcast ones, so peers that missed them too do not each have to wait for
+/// their own digest round.
+#[instrument("Repair", skip_all)]
+fn repair(messages: &Arc<RwLock<SetU32>>, missing: &SetU32, outboxes: &Outboxes) {
+    let new = {
+        let mut mm = messages.write();
+        let new = missing - &mm;
+        *mm = &new | &mm;
+        new
+    };
+    if !new.is_empty() {
+        debug!(count = new.len(), "Repaired missing messages");
+        enqueue(outboxes, &new);
+    }
 }
 
 #[instrument("Read", skip(msg))]
@@ -125,42 +352,123 @@ async fn handle_read(
 #[instrument("Topology", skip(msg))]
 async fn handle_topology(
     topology: &HashMap<CompactString, Vec<CompactString>>,
+    strategy: Topology,
+    plumtree: Option<&Plumtree>,
     node: &Arc<Node>,
-    peers: &Arc<RwLock<Vec<CompactString>>>,
+    outboxes: &Outboxes,
     msg: &Message<Value>,
 ) -> Result<(), NodeError> {
-    if let Some(p) = topology.get(&node.id) {
-        *peers.write() = p.clone();
+    let peers = strategy.peers(&node.id, &node.node_ids, topology);
+    if let Some(plumtree) = plumtree {
+        plumtree.set_peers(peers);
+    } else {
+        let mut outboxes = outboxes.write();
+        outboxes.retain(|peer, _| peers.contains(peer));
+        for peer in peers {
+            outboxes.entry(peer).or_default();
+        }
     }
     node.reply(msg, Response::TopologyOk).await
 }
 
-async fn handle_batch_sending(
-    peers: Arc<RwLock<Vec<CompactString>>>,
-    buffer: Arc<RwLock<SetU32>>,
+/// Every period, sends each peer everything still pending for it in a single
+/// batch, which also acknowledges the peer's batches received since the last
+/// one, so links busy in both directions need no separate acknowledgements.
+/// Batches are not retried on their own: whatever a lost batch held is still
+/// pending and goes out again with the next round, so a slow or partitioned
+/// peer only ever holds up its own delivery.
+///
+/// A batch that would only repeat last round's, which may still be in flight,
+/// is held back a round.
+async fn handle_batch_sending(outboxes: Outboxes, node: Arc<Node>) -> Result<(), NodeError> {
+    for round in 1u64.. {
+        tokio::time::sleep(BATCH_PERIOD).await;
+        let ready = outboxes
+            .write()
+            .iter_mut()
+            .filter_map(|(peer, outbox)| {
+                let acks = std::mem::take(&mut outbox.acks);
+                let repeat = outbox
+                    .unacked
+                    .get(&(round - 1))
+                    .is_some_and(|last| (&outbox.pending - last).is_empty());
+                let msg = if !repeat && !outbox.pending.is_empty() {
+                    outbox.unacked = outbox
+                        .unacked
+                        .split_off(&round.saturating_sub(UNACKED_ROUNDS));
+                    outbox.unacked.insert(round, outbox.pending.clone());
+                    Request::BroadcastBatch {
+                        messages: outbox.pending.clone(),
+                        round,
+                        acks,
+                    }
+                } else if !acks.is_empty() {
+                    Request::BroadcastBatchOk { rounds: acks }
+                } else {
+                    return None;
+                };
+                Some((peer.clone(), msg))
+            })
+            .collect::<Vec<_>>();
+        for (peer, msg) in ready {
+            debug!(peer = peer.as_str(), round, "Sending batch");
+            node.send(peer, msg).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodically sends a digest of everything seen to one node after another,
+/// which answers with whatever the digest is missing. Digests are not retried:
+/// one lost to a partition is simply superseded by the next round.
+///
+/// Only runs with `VORTEX_ANTI_ENTROPY=digest`, which is also the only use of
+/// `VORTEX_MEMBERSHIP=swim`: nodes declared dead are skipped, and a node
+/// coming back gets a digest straight away rather than waiting for its turn.
+async fn handle_anti_entropy(
+    messages: Arc<RwLock<SetU32>>,
+    membership: Option<Arc<Membership>>,
     node: Arc<Node>,
 ) -> Result<(), NodeError> {
+    let others = node
+        .node_ids
+        .iter()
+        .filter(|n| **n != node.id)
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut rotation = others.iter().cycle();
+    let mut events = membership.as_ref().map(|m| m.subscribe());
     loop {
-        async {
-            tokio::time::sleep(BATCH_PERIOD).await;
-            let pending = std::mem::take(&mut *buffer.write());
-            if !pending.is_empty() {
-                let peers = peers.read().clone();
-                for peer in peers {
-                    _ = node
-                        .rpc(
-                            peer,
-                            Request::BroadcastBatch {
-                                messages: pending.clone(),
-                            },
-                        )
-                        .await?;
-                }
+        let peer = tokio::select! {
+            biased;
+            _ = tokio::time::sleep(ANTI_ENTROPY_PERIOD) => {
+                let dead = |peer: &str| {
+                    membership.as_ref().and_then(|m| m.status(peer)) == Some(Status::Dead)
+                };
+                rotation
+                    .by_ref()
+                    .take(others.len())
+                    .find(|peer| !dead(peer))
+                    .cloned()
             }
+            peer = came_back(&mut events) => Some(peer),
+        };
+        if let Some(peer) = peer {
+            let ranges = digest(messages.read().iter());
+            node.send(peer, Request::Digest { ranges }).await?;
+        }
+    }
+}
 
-            Ok(())
+/// Waits for the membership to see a node come back, forever if there is no
+/// membership.
+async fn came_back(events: &mut Option<broadcast::Receiver<Event>>) -> CompactString {
+    while let Some(rx) = events {
+        match rx.recv().await {
+            Ok(Event::Alive(peer)) => return peer,
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => *events = None,
         }
-        .instrument(debug_span!("Sending batch message", id = node.id.as_str()))
-        .await?
     }
+    std::future::pending().await
 }
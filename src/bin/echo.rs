@@ -26,7 +26,7 @@ enum Response {
 async fn main() -> miette::Result<()> {
     init_tracing()?;
 
-    main_loop(handle_msg)?.await
+    main_loop(|_| handle_msg).await
 }
 
 async fn handle_msg(msg: Message<Value>, node: Arc<Node>) -> Result<(), NodeError> {
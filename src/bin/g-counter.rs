@@ -1,14 +1,16 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use rand::Rng;
+use compact_str::{format_compact, CompactString};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tracing::{info, instrument};
 use vortex::{
+    crdt::{GCounter, Gossip, Replica},
     error::{JsonDeError, NodeError},
     init_tracing, main_loop,
     message::Message,
     node::Node,
+    service::{KvClient, KvService},
 };
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -26,48 +28,126 @@ enum Response {
     Error { code: u8, text: String },
 }
 
+type Kv = KvClient<CompactString, u64>;
+
+const PERSIST_PERIOD: Duration = Duration::from_millis(500);
+const MODE_ENV: &str = "VORTEX_COUNTER";
+
+/// Where the counter lives, chosen with `VORTEX_COUNTER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// One key per node in seq-kv.
+    SeqKv,
+    /// One key per node in lin-kv, which needs no barrier to read fresh
+    /// values.
+    LinKv,
+    /// A G-Counter CRDT replicated on every node and gossiped between them.
+    /// Reads are served locally, so they can lag adds made elsewhere.
+    Crdt,
+    /// As `Crdt`, with each node's own count also saved to seq-kv and added
+    /// back when the node starts.
+    PersistentCrdt,
+}
+
+impl Mode {
+    fn from_env() -> Result<Self, NodeError> {
+        match std::env::var(MODE_ENV).as_deref() {
+            Err(_) | Ok("kv" | "seq-kv") => Ok(Mode::SeqKv),
+            Ok("lin-kv") => Ok(Mode::LinKv),
+            Ok("crdt") => Ok(Mode::Crdt),
+            Ok("crdt-persist") => Ok(Mode::PersistentCrdt),
+            Ok(other) => Err(NodeError::new(format!("Unknown counter mode: {other}"))),
+        }
+    }
+
+    /// The KV service holding the counter, if it is kept in one.
+    fn kv(self) -> Option<KvService> {
+        match self {
+            Mode::SeqKv => Some(KvService::Seq),
+            Mode::LinKv => Some(KvService::Lin),
+            Mode::Crdt | Mode::PersistentCrdt => None,
+        }
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> miette::Result<()> {
     init_tracing()?;
-    main_loop(handle_msg)?.await
+    let mode = Mode::from_env()?;
+
+    main_loop(|node| {
+        let counter = Replica::<GCounter>::new(node.clone());
+        let kv = mode
+            .kv()
+            .map(|svc| Arc::new(KvClient::cached(node.clone(), svc)));
+        if kv.is_none() {
+            tokio::spawn(counter.clone().run());
+        }
+        if mode == Mode::PersistentCrdt {
+            tokio::spawn(handle_persist(counter.clone(), node));
+        }
+        move |msg, node| handle_msg(msg, node, counter, kv)
+    })
+    .await
 }
 
-async fn handle_msg(msg: Message<Value>, node: Arc<Node>) -> Result<(), NodeError> {
-    match msg.src.as_str() {
-        "seq-kv" => node.handle_kv(&msg),
-        _ => match Request::de(&msg.body.payload)? {
-            Request::Add { delta } => handle_add(delta, &node, &msg).await,
-            Request::Read => handle_read(&node, &msg).await,
-        },
+async fn handle_msg(
+    msg: Message<Value>,
+    node: Arc<Node>,
+    counter: Arc<Replica<GCounter>>,
+    kv: Option<Arc<Kv>>,
+) -> Result<(), NodeError> {
+    if Gossip::matches(&msg) {
+        return counter.handle(&msg).await;
+    }
+    if KvService::from_name(&msg.src).is_some() {
+        return node.handle_kv(&msg);
+    }
+    match (Request::de(&msg.body.payload)?, kv) {
+        (Request::Add { delta }, Some(kv)) => handle_add(delta, &kv, &node, &msg).await,
+        (Request::Add { delta }, None) => {
+            counter.update(|c| c.increment(&node.id, delta));
+            node.reply(&msg, Response::AddOk).await
+        }
+        (Request::Read, Some(kv)) => handle_read(&kv, &node, &msg).await,
+        (Request::Read, None) => {
+            let value = counter.read(GCounter::value);
+            node.reply(&msg, Response::ReadOk { value }).await
+        }
     }
 }
 
-#[instrument("Add", skip(msg))]
-async fn handle_add(delta: u64, node: &Arc<Node>, msg: &Message<Value>) -> Result<(), NodeError> {
-    let id = node.id.as_str();
-    let val = node
-        .kv_read("seq-kv", id)
-        .await?
-        .map(u64::de)
-        .unwrap_or(Ok(0))?;
-    node.kv_write("seq-kv", id, val + delta).await?;
+/// Each node only adds to its own key, but concurrent adds on the same node
+/// still race, so the key is updated atomically. As no other node writes the
+/// key, the cached value is almost always current and saves the read.
+#[instrument("Add", skip(node, msg))]
+async fn handle_add(
+    delta: u64,
+    kv: &Kv,
+    node: &Arc<Node>,
+    msg: &Message<Value>,
+) -> Result<(), NodeError> {
+    kv.update(&node.id, |old| old.copied().unwrap_or(0) + delta)
+        .await?;
 
     node.reply(msg, Response::AddOk).await
 }
 
-#[instrument("Read", skip(msg))]
-async fn handle_read(node: &Arc<Node>, msg: &Message<Value>) -> Result<(), NodeError> {
-    node.kv_write(
-        "seq-kv",
-        format!("barrier:{}", rand::thread_rng().gen::<u32>()),
-        0,
-    )
-    .await?;
+/// Sums every node's key. seq-kv only promises that a client's reads are at
+/// least as new as its own last write, so a read first writes this node's
+/// barrier key: the reads after it then reflect every add acknowledged
+/// before the read began.
+#[instrument("Read", skip(node, msg))]
+async fn handle_read(kv: &Kv, node: &Arc<Node>, msg: &Message<Value>) -> Result<(), NodeError> {
+    if kv.svc() == KvService::Seq {
+        let barrier = format_compact!("barrier:{}", node.id);
+        kv.write(&barrier, &0).await?;
+    }
 
     let mut value = 0;
     for id in &node.node_ids {
-        value += match node.kv_read("seq-kv", id.as_str()).await? {
-            Some(v) => u64::de(v)?,
+        value += match kv.read(id).await? {
+            Some(v) => v,
             None => {
                 info!("Key not found: {id}");
                 0
@@ -76,3 +156,25 @@ async fn handle_read(node: &Arc<Node>, msg: &Message<Value>) -> Result<(), NodeE
     }
     node.reply(msg, Response::ReadOk { value }).await
 }
+
+/// Adds back the count saved by an earlier run of this node, then saves the
+/// node's own count whenever it changes.
+async fn handle_persist(counter: Arc<Replica<GCounter>>, node: Arc<Node>) -> Result<(), NodeError> {
+    let kv = Kv::new(node.clone(), KvService::Seq);
+    let key = format_compact!("crdt:{}", node.id);
+    let mut saved = 0;
+    if let Some(v) = kv.read(&key).await? {
+        saved = v;
+        info!(saved, "Restored count");
+        counter.update(|c| c.increment(&node.id, saved));
+    }
+
+    loop {
+        tokio::time::sleep(PERSIST_PERIOD).await;
+        let own = counter.read(|c| c.get(&node.id));
+        if own != saved {
+            kv.write(&key, &own).await?;
+            saved = own;
+        }
+    }
+}
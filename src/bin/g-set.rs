@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use vortex::{
+    crdt::{GSet, Gossip, Json, Replica},
+    error::{JsonDeError, NodeError},
+    init_tracing, main_loop,
+    message::Message,
+    node::Node,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Add { element: Value },
+    Read,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    AddOk,
+    ReadOk { value: Vec<Value> },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> miette::Result<()> {
+    init_tracing()?;
+
+    main_loop(|node| {
+        let set = Replica::<GSet<Json>>::new(node);
+        tokio::spawn(set.clone().run());
+        move |msg, node| handle_msg(msg, node, set)
+    })
+    .await
+}
+
+async fn handle_msg(
+    msg: Message<Value>,
+    node: Arc<Node>,
+    set: Arc<Replica<GSet<Json>>>,
+) -> Result<(), NodeError> {
+    if Gossip::matches(&msg) {
+        return set.handle(&msg).await;
+    }
+    match Request::de(&msg.body.payload)? {
+        Request::Add { element } => {
+            set.update(|s| s.insert(Json(element)));
+            node.reply(&msg, Response::AddOk).await
+        }
+        Request::Read => {
+            let value = set.read(|s| s.iter().map(|e| e.0.clone()).collect());
+            node.reply(&msg, Response::ReadOk { value }).await
+        }
+    }
+}
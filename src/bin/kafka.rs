@@ -15,10 +15,20 @@ use vortex::{
     init_tracing, main_loop,
     message::Message,
     node::Node,
+    service::{KvClient, KvService},
 };
 
 type Logs = HashMap<CompactString, Vec<(u64, u64)>>;
 type State = DashMap<CompactString, BTreeMap<u64, u64>>;
+type Kv = KvClient<CompactString, u64>;
+
+/// The lin-kv clients. Every node bumps the `<key>:offset` counters, so a
+/// cached value would nearly always be stale and cost a failed swap, while
+/// `<key>:committed` mostly changes on the node a consumer talks to.
+struct Kvs {
+    offsets: Kv,
+    committed: Kv,
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -64,25 +74,35 @@ enum Response {
 async fn main() -> miette::Result<()> {
     init_tracing()?;
 
-    let logs = Arc::new(DashMap::new());
-    main_loop(move |msg, node| handle_msg(msg, node, logs))?.await
+    main_loop(|node| {
+        let logs = Arc::new(DashMap::new());
+        let kv = Arc::new(Kvs {
+            offsets: Kv::new(node.clone(), KvService::Lin),
+            committed: Kv::cached(node, KvService::Lin),
+        });
+        move |msg, node| handle_msg(msg, node, logs, kv)
+    })
+    .await
 }
 
 async fn handle_msg(
     msg: Message<Value>,
     node: Arc<Node>,
     logs: Arc<State>,
+    kv: Arc<Kvs>,
 ) -> Result<(), NodeError> {
-    match msg.src.as_str() {
-        "seq-kv" | "lin-kv" => node.handle_kv(&msg),
-        _ => match Request::de(&msg.body.payload)? {
+    match KvService::from_name(&msg.src) {
+        Some(_) => node.handle_kv(&msg),
+        None => match Request::de(&msg.body.payload)? {
             Request::Send { key, msg: message } => {
-                handle_send(key, message, &node, &msg, &logs).await
+                handle_send(key, message, &node, &msg, &logs, &kv.offsets).await
             }
             Request::Poll { offsets } => handle_poll(offsets, &node, &msg, &logs).await,
-            Request::CommitOffsets { offsets } => handle_commit(offsets, &node, &msg).await,
+            Request::CommitOffsets { offsets } => {
+                handle_commit(offsets, &node, &msg, &kv.committed).await
+            }
             Request::ListCommittedOffsets { keys } => {
-                handle_list_committed(keys, &node, &msg).await
+                handle_list_committed(keys, &node, &msg, &kv.committed).await
             }
             Request::Query { offsets } => handle_query(offsets, &node, &msg, &logs).await,
             Request::QueryOk { query_logs } => handle_query_ok(&node, &msg, query_logs).await,
@@ -97,25 +117,12 @@ async fn handle_send(
     node: &Arc<Node>,
     msg: &Message<Value>,
     logs: &Arc<State>,
+    kv: &Kv,
 ) -> Result<(), NodeError> {
     let key_offset = format_compact!("{key}:offset");
-    let mut kv_offset = node.kv_read("lin-kv", key_offset.as_str()).await?;
-    let mut offset = kv_offset.as_ref().map_or_else(|| Ok(0), u64::de)?;
-    offset += 1;
-
-    while !node
-        .kv_cas("lin-kv", key_offset.as_str(), kv_offset, offset)
-        .await?
-    {
-        let s = node
-            .kv_read("lin-kv", key_offset.as_str())
-            .await?
-            .with_reason("Failed to read after CAS")?;
-        offset = u64::de(&s)?;
-        offset += 1;
-
-        kv_offset = Some(s);
-    }
+    let offset = kv
+        .update(&key_offset, |old| old.map_or(1, |o| o + 1))
+        .await?;
     logs.entry(key).or_default().insert(offset, message);
 
     node.reply(msg, Response::SendOk { offset }).await
@@ -128,10 +135,17 @@ async fn handle_poll(
     msg: &Message<Value>,
     logs: &Arc<State>,
 ) -> Result<(), NodeError> {
-    let mut queries = node
+    let (peers, suspected): (Vec<_>, Vec<_>) = node
         .node_ids
         .iter()
         .filter(|&id| id != &node.id)
+        .partition(|&id| !node.health.is_suspected(id));
+    if !suspected.is_empty() {
+        debug!(?suspected, "Skipping suspected peers");
+    }
+
+    let mut queries = peers
+        .into_iter()
         .map(|id| async {
             node.rpc(
                 id.clone(),
@@ -153,11 +167,29 @@ async fn handle_poll(
             },
         )
         .await?;
-    offsets.into_iter().for_each(|(key, offset)| {
-        if let Some(log) = logs.get(&key) {
-            queries.entry(key).or_default().extend(log.range(offset..))
+    offsets.iter().for_each(|(key, &offset)| {
+        if let Some(log) = logs.get(key) {
+            queries
+                .entry(key.clone())
+                .or_default()
+                .extend(log.range(offset..))
         }
     });
+    if !suspected.is_empty() {
+        // Offsets are handed out densely from 1, so a gap is a message held by
+        // a skipped peer. Stop before it, or the client would poll past it.
+        for (key, log) in queries.iter_mut() {
+            let mut next = offsets.get(key).copied().unwrap_or(0).max(1);
+            let end = log.keys().find(|&&o| {
+                let gap = o != next;
+                next = o + 1;
+                gap
+            });
+            if let Some(&end) = end {
+                log.split_off(&end);
+            }
+        }
+    }
 
     let msgs = queries
         .into_iter()
@@ -172,36 +204,12 @@ async fn handle_commit(
     offsets: HashMap<CompactString, u64>,
     node: &Arc<Node>,
     msg: &Message<Value>,
+    kv: &Kv,
 ) -> Result<(), NodeError> {
     for (key, val) in offsets {
         let key = format_compact!("{key}:committed");
-        let kv_committed = node.kv_read("lin-kv", key.as_str()).await?;
-
-        if let Some(mut kv_committed) = kv_committed {
-            let mut committed = u64::de(&kv_committed)?;
-            if committed >= val {
-                debug!("Already committed");
-                continue;
-            }
-
-            while !node
-                .kv_cas("lin-kv", key.as_str(), kv_committed, val)
-                .await?
-            {
-                let s = node
-                    .kv_read("lin-kv", key.as_str())
-                    .await?
-                    .with_reason("Failed to read after CAS")?;
-
-                committed = u64::de(&s)?;
-                if committed >= val {
-                    debug!("Already committed");
-                    break;
-                }
-
-                kv_committed = val.into();
-            }
-        }
+        kv.update(&key, |old| old.map_or(val, |&o| o.max(val)))
+            .await?;
     }
 
     node.reply(msg, Response::CommitOffsetsOk {}).await
@@ -212,15 +220,14 @@ async fn handle_list_committed(
     keys: Vec<CompactString>,
     node: &Arc<Node>,
     msg: &Message<Value>,
+    kv: &Kv,
 ) -> Result<(), NodeError> {
     let offsets = keys
         .into_iter()
         .map(|key| async move {
             let k = format_compact!("{key}:committed");
-            let kv_committed = node.kv_read("lin-kv", k.as_str()).await;
-            kv_committed
-                .transpose()
-                .map(|v| v.and_then(u64::de).map(|v| (k, v)))
+            let committed = kv.read(&k).await;
+            committed.transpose().map(|v| v.map(|v| (key, v)))
         })
         .collect::<FuturesUnordered<_>>()
         .filter_map(future::ready)
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use vortex::{
+    crdt::{Gossip, PNCounter, Replica},
+    error::{JsonDeError, NodeError},
+    init_tracing, main_loop,
+    message::Message,
+    node::Node,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Add { delta: i64 },
+    Read,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    AddOk,
+    ReadOk { value: i64 },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> miette::Result<()> {
+    init_tracing()?;
+
+    main_loop(|node| {
+        let counter = Replica::<PNCounter>::new(node);
+        tokio::spawn(counter.clone().run());
+        move |msg, node| handle_msg(msg, node, counter)
+    })
+    .await
+}
+
+async fn handle_msg(
+    msg: Message<Value>,
+    node: Arc<Node>,
+    counter: Arc<Replica<PNCounter>>,
+) -> Result<(), NodeError> {
+    if Gossip::matches(&msg) {
+        return counter.handle(&msg).await;
+    }
+    match Request::de(&msg.body.payload)? {
+        Request::Add { delta } => {
+            counter.update(|c| c.add(&node.id, delta));
+            node.reply(&msg, Response::AddOk).await
+        }
+        Request::Read => {
+            let value = counter.read(PNCounter::value);
+            node.reply(&msg, Response::ReadOk { value }).await
+        }
+    }
+}
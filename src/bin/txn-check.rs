@@ -0,0 +1,37 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use miette::{bail, miette, IntoDiagnostic};
+use vortex::checker::txn::{check, ConsistencyModel, Txn};
+
+fn main() -> miette::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .ok_or_else(|| miette!("Usage: txn-check <history.jsonl> [consistency-model]"))?;
+    let model = args
+        .next()
+        .as_deref()
+        .unwrap_or("serializable")
+        .parse::<ConsistencyModel>()?;
+
+    let history = BufReader::new(File::open(path).into_diagnostic()?)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| serde_json::from_str::<Txn>(&line.into_diagnostic()?).into_diagnostic())
+        .collect::<miette::Result<Vec<_>>>()?;
+
+    let report = check(&history);
+    for anomaly in &report.anomalies {
+        println!("{anomaly}");
+    }
+
+    let violations = report.violations(model).count();
+    if violations > 0 {
+        bail!("{violations} anomalies prohibited by {model}");
+    }
+    println!("{} transactions valid under {model}", history.len());
+    Ok(())
+}
@@ -1,33 +1,29 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
+use compact_str::{format_compact, CompactString};
 use dashmap::DashMap;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use serde_tuple::{Deserialize_tuple, Serialize_tuple};
-use tracing::instrument;
+use tracing::{debug, instrument};
 use vortex::{
     error::{JsonDeError, NodeError},
     init_tracing, main_loop,
     message::Message,
     node::Node,
+    service::{CasOutcome, KvClient, KvService, TSO},
+    txn::{Op, OpType},
 };
 
 type State = DashMap<u64, u64>;
+type Registers = Arc<BTreeMap<u64, u64>>;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-enum OpType {
-    #[serde(rename = "r")]
-    Read,
-    #[serde(rename = "w")]
-    Write,
-}
-
-#[derive(Deserialize_tuple, Serialize_tuple, Debug, Clone)]
-struct Op {
-    kind: OpType,
-    key: u64,
-    val: Option<u64>,
-}
+const MODE_ENV: &str = "VORTEX_TXN";
+const ROOT: &str = "mvcc:root";
+const TXN_CONFLICT: u8 = 30;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -39,25 +35,227 @@ enum Request {
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Response {
     TxnOk { txn: Vec<Op> },
+    Error { code: u8, text: String },
+}
+
+/// Where the registers live, chosen with `VORTEX_TXN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// In memory on each node, applied in place with no isolation between
+    /// nodes.
+    Local,
+    /// Versioned in lin-kv by timestamps from lin-tso, see [`Mvcc`].
+    Mvcc,
+}
+
+impl Mode {
+    fn from_env() -> Result<Self, NodeError> {
+        match std::env::var(MODE_ENV).as_deref() {
+            Err(_) | Ok("local") => Ok(Mode::Local),
+            Ok("mvcc") => Ok(Mode::Mvcc),
+            Ok(other) => Err(NodeError::new(format!("Unknown txn mode: {other}"))),
+        }
+    }
+}
+
+/// How a transaction that lost the swap of the root stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rebase {
+    /// A retransmitted swap had already made it the root.
+    Committed,
+    /// A newer commit wrote a register it read or wrote.
+    Conflict,
+    /// Nothing it read or wrote has changed up to this root.
+    Onto(Option<u64>),
+}
+
+/// The registers one commit wrote, saved once to lin-kv under the commit's
+/// timestamp and never changed.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+struct Version {
+    /// The commit this one replaced as the root.
+    parent: Option<u64>,
+    writes: BTreeMap<u64, u64>,
+}
+
+/// Multi-version storage in lin-kv. `mvcc:root` holds the timestamp of the
+/// latest commit, and each commit points at the one before it, so the
+/// commits form a single chain. A transaction runs against the registers as
+/// of the root, and if it wrote anything, saves its writes as a new version
+/// under a timestamp from lin-tso and swaps the root over to it. Losing that
+/// swap aborts the transaction only if one of the commits that won it since
+/// touched a register the transaction read or wrote. Otherwise the reads
+/// still hold as of the new root, and the writes are saved again on top of
+/// it. Commits are serializable, and read-only transactions never conflict.
+///
+/// Storage grows with the number of registers written, not with the size of
+/// the state. An aborted transaction's version is never reachable from the
+/// root, but lin-kv cannot delete keys, so it stays behind.
+struct Mvcc {
+    node: Arc<Node>,
+    root: KvClient<CompactString, u64>,
+    versions: KvClient<CompactString, Version>,
+    /// The newest registers this node has built, and the commit they are as
+    /// of, so each version is only read once to move them forward.
+    snapshot: Mutex<(Option<u64>, Registers)>,
+}
+
+impl Mvcc {
+    fn new(node: Arc<Node>) -> Self {
+        Self {
+            root: KvClient::new(node.clone(), KvService::Lin),
+            versions: KvClient::new(node.clone(), KvService::Lin),
+            snapshot: Mutex::default(),
+            node,
+        }
+    }
+
+    async fn version(&self, ts: u64) -> Result<Version, NodeError> {
+        let key = format_compact!("mvcc:{ts}");
+        self.versions
+            .read(&key)
+            .await?
+            .ok_or_else(|| NodeError::new(format_compact!("Version {ts} does not exist")))
+    }
+
+    /// Every register as of commit `ts`, from the snapshot plus the writes of
+    /// each commit since it.
+    async fn registers(&self, ts: Option<u64>) -> Result<Registers, NodeError> {
+        // Timestamps grow along the chain, so an older snapshot is an
+        // ancestor of `ts`, and a newer one cannot be rolled back.
+        let (base, values) = match self.snapshot.lock().clone() {
+            (base, values) if base <= ts => (base, values),
+            _ => (None, Arc::default()),
+        };
+        let mut chain = vec![];
+        let mut at = ts;
+        while at != base {
+            let Some(cur) = at else {
+                return Err(NodeError::new(format_compact!(
+                    "Commit {base:?} is not an ancestor of {ts:?}"
+                )));
+            };
+            let version = self.version(cur).await?;
+            at = version.parent;
+            chain.push(version);
+        }
+        if chain.is_empty() {
+            return Ok(values);
+        }
+
+        let mut values = (*values).clone();
+        for version in chain.into_iter().rev() {
+            values.extend(version.writes);
+        }
+        let values = Arc::new(values);
+        let mut snapshot = self.snapshot.lock();
+        if snapshot.0 < ts {
+            *snapshot = (ts, values.clone());
+        }
+        Ok(values)
+    }
+
+    /// Runs `txn` and returns whether it committed.
+    async fn apply(&self, txn: &mut [Op]) -> Result<bool, NodeError> {
+        let mut root = self.root.read(&ROOT.into()).await?;
+        let mut values = (*self.registers(root).await?).clone();
+        apply(txn, &mut values);
+        let writes = txn
+            .iter()
+            .filter(|op| op.kind == OpType::Write)
+            .filter_map(|op| Some((op.key, op.val?)))
+            .collect::<BTreeMap<_, _>>();
+        if writes.is_empty() {
+            return Ok(true);
+        }
+
+        let keys = txn.iter().map(|op| op.key).collect::<BTreeSet<_>>();
+        loop {
+            // Timestamps must grow along the chain, so every attempt takes a
+            // new one.
+            let ts = self.node.tso_next().await?;
+            let version = Version {
+                parent: root,
+                writes: writes.clone(),
+            };
+            let key = format_compact!("mvcc:{ts}");
+            self.versions.write(&key, &version).await?;
+            match self.root.cas(&ROOT.into(), root.as_ref(), &ts).await? {
+                CasOutcome::Swapped => return Ok(true),
+                CasOutcome::Mismatch | CasOutcome::Missing => {}
+            }
+            match self.rebase(root, ts, &keys).await? {
+                Rebase::Committed => return Ok(true),
+                Rebase::Conflict => return Ok(false),
+                Rebase::Onto(cur) => {
+                    debug!(?root, ?cur, "Rebasing onto a disjoint commit");
+                    root = cur;
+                }
+            }
+        }
+    }
+
+    /// Walks the commits from the root back to `base`, the root that commit
+    /// `ts` failed to replace, checking whether any of them is `ts` itself,
+    /// as when the failed swap was the retransmission of one that succeeded,
+    /// or wrote one of `keys`.
+    async fn rebase(
+        &self,
+        base: Option<u64>,
+        ts: u64,
+        keys: &BTreeSet<u64>,
+    ) -> Result<Rebase, NodeError> {
+        let root = self.root.read(&ROOT.into()).await?;
+        let mut conflict = false;
+        let mut at = root;
+        while at != base {
+            let Some(cur) = at else {
+                return Err(NodeError::new(format_compact!(
+                    "Commit {base:?} is not an ancestor of {root:?}"
+                )));
+            };
+            if cur == ts {
+                return Ok(Rebase::Committed);
+            }
+            let version = self.version(cur).await?;
+            conflict |= version.writes.keys().any(|k| keys.contains(k));
+            at = version.parent;
+        }
+        Ok(if conflict {
+            Rebase::Conflict
+        } else {
+            Rebase::Onto(root)
+        })
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> miette::Result<()> {
     init_tracing()?;
+    let mode = Mode::from_env()?;
 
-    let state = Arc::new(State::new());
-    main_loop(move |msg, node| handle_msg(msg, node, state))?.await
+    main_loop(|node| {
+        let state = Arc::new(State::new());
+        let mvcc = (mode == Mode::Mvcc).then(|| Arc::new(Mvcc::new(node)));
+        move |msg, node| handle_msg(msg, node, state, mvcc)
+    })
+    .await
 }
 
 async fn handle_msg(
     msg: Message<Value>,
     node: Arc<Node>,
     state: Arc<State>,
+    mvcc: Option<Arc<Mvcc>>,
 ) -> Result<(), NodeError> {
-    match msg.src.as_str() {
-        "seq-kv" | "lin-kv" => node.handle_kv(&msg),
-        _ => match Request::de(&msg.body.payload)? {
-            Request::Txn { txn } => handle_txn(txn, &node, &msg, &state).await,
+    if msg.src == TSO {
+        return node.handle_tso(&msg);
+    }
+    match KvService::from_name(&msg.src) {
+        Some(_) => node.handle_kv(&msg),
+        None => match (Request::de(&msg.body.payload)?, mvcc) {
+            (Request::Txn { txn }, Some(mvcc)) => handle_mvcc_txn(txn, &node, &msg, &mvcc).await,
+            (Request::Txn { txn }, None) => handle_txn(txn, &node, &msg, &state).await,
         },
     }
 }
@@ -80,3 +278,183 @@ async fn handle_txn(
 
     node.reply(msg, Response::TxnOk { txn }).await
 }
+
+#[instrument("MVCC Txn", skip(txn, mvcc))]
+async fn handle_mvcc_txn(
+    mut txn: Vec<Op>,
+    node: &Arc<Node>,
+    msg: &Message<Value>,
+    mvcc: &Mvcc,
+) -> Result<(), NodeError> {
+    if mvcc.apply(&mut txn).await? {
+        node.reply(msg, Response::TxnOk { txn }).await
+    } else {
+        debug!("Conflicting commit");
+        let res = Response::Error {
+            code: TXN_CONFLICT,
+            text: "Transaction conflicted with a concurrent commit".into(),
+        };
+        node.reply(msg, res).await
+    }
+}
+
+fn apply(txn: &mut [Op], values: &mut BTreeMap<u64, u64>) {
+    txn.iter_mut().for_each(|op| match &op.kind {
+        OpType::Read => op.val = values.get(&op.key).copied(),
+        OpType::Write => {
+            if let Some(val) = op.val {
+                values.insert(op.key, val);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use vortex::{message::Init, serve, sim::Network};
+
+    use super::*;
+
+    /// MVCC storage on a node of its own, served lin-kv and lin-tso by `net`.
+    fn mvcc(net: &Network, id: &str) -> Arc<Mvcc> {
+        let init = Init {
+            node_id: id.into(),
+            node_ids: vec!["n0".into(), "n1".into()],
+        };
+        let node = Arc::new(Node::from_init(init, net.sender()));
+        let mvcc = Arc::new(Mvcc::new(node.clone()));
+        let (state, m) = (Arc::new(State::new()), mvcc.clone());
+        let rx = net.register(id);
+        tokio::spawn(serve(node, rx, move |msg, node| {
+            handle_msg(msg, node, state, Some(m))
+        }));
+        mvcc
+    }
+
+    fn net() -> Network {
+        Network::with_services(Duration::from_millis(1), 0)
+    }
+
+    fn r(key: u64) -> Op {
+        Op {
+            kind: OpType::Read,
+            key,
+            val: None,
+        }
+    }
+
+    fn w(key: u64, val: u64) -> Op {
+        Op {
+            kind: OpType::Write,
+            key,
+            val: Some(val),
+        }
+    }
+
+    fn vals(txn: &[Op]) -> Vec<Option<u64>> {
+        txn.iter().map(|op| op.val).collect()
+    }
+
+    async fn root(mvcc: &Mvcc) -> u64 {
+        mvcc.root.read(&ROOT.into()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn versions_hold_only_their_writes() {
+        let net = net();
+        let (a, b) = (mvcc(&net, "n0"), mvcc(&net, "n1"));
+        assert!(a.apply(&mut [w(1, 1), w(2, 1)]).await.unwrap());
+        let first = root(&a).await;
+        assert!(a.apply(&mut [r(1), w(2, 2), w(2, 3)]).await.unwrap());
+
+        let mut txn = [r(1), r(2), r(3)];
+        assert!(b.apply(&mut txn).await.unwrap());
+        assert_eq!(vals(&txn), [Some(1), Some(3), None]);
+        let version = b.version(root(&b).await).await.unwrap();
+        assert_eq!(version.parent, Some(first));
+        assert_eq!(version.writes, BTreeMap::from([(2, 3)]));
+
+        // A snapshot newer than the commit asked for is not rolled back.
+        let older = a.registers(Some(first)).await.unwrap();
+        assert_eq!(*older, BTreeMap::from([(1, 1), (2, 1)]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_commits_conflict() {
+        let net = net();
+        let (a, b) = (mvcc(&net, "n0"), mvcc(&net, "n1"));
+        let (mut t1, mut t2) = ([r(1), w(1, 1)], [r(1), w(1, 2)]);
+        let (c1, c2) = tokio::join!(a.apply(&mut t1), b.apply(&mut t2));
+        let (c1, c2) = (c1.unwrap(), c2.unwrap());
+        assert_ne!(c1, c2, "both read the empty root, so only one may commit");
+
+        let mut txn = [r(1)];
+        assert!(b.apply(&mut txn).await.unwrap());
+        assert_eq!(vals(&txn), [Some(if c1 { 1 } else { 2 })]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disjoint_commits_are_rebased() {
+        let net = net();
+        let (a, b) = (mvcc(&net, "n0"), mvcc(&net, "n1"));
+        assert!(a.apply(&mut [w(3, 0)]).await.unwrap());
+        let (mut t1, mut t2) = ([r(1), w(1, 1), r(3)], [r(2), w(2, 2), r(3)]);
+        let (c1, c2) = tokio::join!(a.apply(&mut t1), b.apply(&mut t2));
+        assert!(
+            c1.unwrap() && c2.unwrap(),
+            "neither touched the other's keys"
+        );
+
+        let mut txn = [r(1), r(2), r(3)];
+        assert!(a.apply(&mut txn).await.unwrap());
+        assert_eq!(vals(&txn), [Some(1), Some(2), Some(0)]);
+        // One of them was saved again on top of the other.
+        let mut chain = vec![];
+        let mut at = Some(root(&a).await);
+        while let Some(ts) = at {
+            let version = a.version(ts).await.unwrap();
+            at = version.parent;
+            chain.push(version.writes);
+        }
+        chain[..2].sort();
+        let writes = [[(1, 1)], [(2, 2)], [(3, 0)]].map(BTreeMap::from);
+        assert_eq!(chain, writes);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rebase_finds_retransmitted_commits_and_conflicts() {
+        let net = net();
+        let a = mvcc(&net, "n0");
+        let mut commits = vec![];
+        let mut lost = vec![];
+        for key in 1..=3 {
+            let base = a.root.read(&ROOT.into()).await.unwrap();
+            assert!(a.apply(&mut [w(key, 1)]).await.unwrap());
+            commits.push((base, root(&a).await));
+            // Saved like a commit whose swap then lost.
+            let ts = a.node.tso_next().await.unwrap();
+            let version = Version {
+                parent: Some(root(&a).await),
+                writes: BTreeMap::from([(key, 0)]),
+            };
+            let key = format_compact!("mvcc:{ts}");
+            a.versions.write(&key, &version).await.unwrap();
+            lost.push((version.parent, ts));
+        }
+        let keys = BTreeSet::from([2]);
+        for (base, ts) in commits {
+            let rebase = a.rebase(base, ts, &keys).await.unwrap();
+            assert_eq!(rebase, Rebase::Committed, "{ts} committed");
+        }
+        let root = Some(root(&a).await);
+        let mut rebased = vec![];
+        for (base, ts) in lost {
+            rebased.push(a.rebase(base, ts, &keys).await.unwrap());
+        }
+        // Only the commit right after the first lost one wrote key 2.
+        let onto = Rebase::Onto(root);
+        assert_eq!(rebased, [Rebase::Conflict, onto, onto]);
+    }
+}
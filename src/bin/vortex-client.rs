@@ -0,0 +1,34 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use vortex::{
+    client::Opts,
+    init_tracing,
+    sim::{run_processes, run_tcp},
+};
+
+/// Drives a workload against a cluster of node binaries over stdin/stdout or
+/// TCP, without Maelstrom.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Node binary to start `--node-count` copies of.
+    #[arg(long, required_unless_present = "connect")]
+    bin: Option<PathBuf>,
+    /// Addresses of running nodes, started with `VORTEX_LISTEN`, to use
+    /// instead of `--bin`.
+    #[arg(long, num_args = 1.., conflicts_with = "bin")]
+    connect: Vec<String>,
+    #[command(flatten)]
+    opts: Opts,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> miette::Result<()> {
+    init_tracing()?;
+
+    let args = Args::parse();
+    match &args.bin {
+        Some(bin) => run_processes(&args.opts, bin).await,
+        None => run_tcp(&args.opts, &args.connect).await,
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+use crate::client::Call;
+
+/// Every acknowledged broadcast must show up in every final read, and no read
+/// may return a value nobody tried to broadcast.
+pub fn check(history: &[Call]) -> Vec<String> {
+    let mut acked = BTreeSet::new();
+    let mut attempted = BTreeSet::new();
+    for call in history.iter().filter(|c| c.req_type() == "broadcast") {
+        let Some(message) = call.req.get("message").and_then(Value::as_u64) else {
+            continue;
+        };
+        attempted.insert(message);
+        if call.ok().is_some() {
+            acked.insert(message);
+        }
+    }
+
+    let mut violations = vec![];
+    for call in history.iter().filter(|c| c.req_type() == "read") {
+        let Some(messages) = call.ok().map(read_messages) else {
+            if call.is_final {
+                violations.push(format!("Final read on {} did not complete", call.node));
+            }
+            continue;
+        };
+        let unexpected = messages.difference(&attempted).collect::<Vec<_>>();
+        if !unexpected.is_empty() {
+            violations.push(format!(
+                "{} read values never broadcast: {unexpected:?}",
+                call.node
+            ));
+        }
+        if call.is_final {
+            let lost = acked.difference(&messages).collect::<Vec<_>>();
+            if !lost.is_empty() {
+                violations.push(format!(
+                    "{} lost {} acknowledged values: {lost:?}",
+                    call.node,
+                    lost.len()
+                ));
+            }
+        }
+    }
+    violations
+}
+
+pub(crate) fn read_messages(res: &Value) -> BTreeSet<u64> {
+    res.get("messages")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_u64)
+        .collect()
+}
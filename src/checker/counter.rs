@@ -0,0 +1,146 @@
+use serde_json::Value;
+
+use crate::client::{Call, Outcome};
+
+/// Reads must fall between the sum of every attempted negative add and the
+/// sum of every attempted positive one, and final reads must equal the
+/// acknowledged adds plus some of the indeterminate ones.
+///
+/// With `fresh`, every read must also reflect every add acknowledged before
+/// it began, plus only adds that had begun before it ended.
+pub fn check(history: &[Call], fresh: bool) -> Vec<String> {
+    let (mut acked, mut lower, mut upper) = (0, 0, 0);
+    let (mut floor, mut ceiling) = (0, 0);
+    for call in history.iter().filter(|c| c.req_type() == "add") {
+        let delta = call.req.get("delta").and_then(Value::as_i64).unwrap_or(0);
+        match call.outcome() {
+            Outcome::Ok => acked += delta,
+            Outcome::Info if delta < 0 => lower += delta,
+            Outcome::Info => upper += delta,
+            Outcome::Fail => continue,
+        }
+        if delta < 0 {
+            floor += delta;
+        } else {
+            ceiling += delta;
+        }
+    }
+    let (lower, upper) = (acked + lower, acked + upper);
+
+    let mut violations = vec![];
+    for call in history.iter().filter(|c| c.req_type() == "read") {
+        let value = call
+            .ok()
+            .and_then(|res| res.get("value"))
+            .and_then(Value::as_i64);
+        match value {
+            Some(v) if v > ceiling => violations.push(format!(
+                "{} read {v}, more than the {ceiling} ever added",
+                call.node
+            )),
+            Some(v) if v < floor => violations.push(format!(
+                "{} read {v}, less than the {floor} ever subtracted",
+                call.node
+            )),
+            Some(v) if call.is_final && v < lower => violations.push(format!(
+                "{} finally read {v}, but {lower} was acknowledged",
+                call.node
+            )),
+            Some(v) if call.is_final && v > upper => violations.push(format!(
+                "{} finally read {v}, but at most {upper} was acknowledged",
+                call.node
+            )),
+            None if call.is_final => {
+                violations.push(format!("Final read on {} did not complete", call.node))
+            }
+            _ => {}
+        }
+        if let Some(v) = value.filter(|_| fresh) {
+            let (lo, hi) = fresh_bounds(history, call);
+            if v < lo || v > hi {
+                violations.push(format!(
+                    "{} read {v} at {}us, but only {lo}..={hi} was possible by then",
+                    call.node, call.start
+                ));
+            }
+        }
+    }
+    violations
+}
+
+/// The values a read could return if it saw every add completed before it
+/// and any subset of those concurrent with it.
+fn fresh_bounds(history: &[Call], read: &Call) -> (i64, i64) {
+    let (mut lo, mut hi) = (0, 0);
+    for call in history.iter().filter(|c| c.req_type() == "add") {
+        let delta = call.req.get("delta").and_then(Value::as_i64).unwrap_or(0);
+        match call.outcome() {
+            Outcome::Ok if call.end < read.start => {
+                lo += delta;
+                hi += delta;
+            }
+            Outcome::Fail => {}
+            _ if call.start < read.end => {
+                if delta < 0 {
+                    lo += delta;
+                } else {
+                    hi += delta;
+                }
+            }
+            _ => {}
+        }
+    }
+    (lo, hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn add(delta: i64, start: u64, end: u64, res: Option<Value>) -> Call {
+        Call {
+            process: 0,
+            node: "n0".into(),
+            start,
+            end,
+            req: json!({"type": "add", "delta": delta}),
+            res,
+            is_final: false,
+        }
+    }
+
+    fn read(value: i64, start: u64, end: u64) -> Call {
+        Call {
+            req: json!({"type": "read"}),
+            res: Some(json!({"type": "read_ok", "value": value})),
+            ..add(0, start, end, None)
+        }
+    }
+
+    #[test]
+    fn fresh_bounds_count_completed_and_concurrent_adds() {
+        let ok = Some(json!({"type": "add_ok"}));
+        let failed = Some(json!({"type": "error", "code": 11}));
+        let history = [
+            // Acknowledged before the read began: always seen.
+            add(5, 0, 10, ok.clone()),
+            // Acknowledged while the read ran: maybe seen.
+            add(-2, 5, 15, ok.clone()),
+            // Indeterminate, begun while the read ran: maybe seen.
+            add(7, 11, 40, None),
+            // Begun after the read ended: never seen.
+            add(3, 20, 25, ok),
+            // Failed: never seen.
+            add(4, 0, 1, failed),
+            read(8, 12, 18),
+        ];
+        assert_eq!(fresh_bounds(&history, &history[5]), (3, 12));
+
+        assert_eq!(check(&history, true), Vec::<String>::new());
+        let stale = [&history[..5], &[read(2, 12, 18)]].concat();
+        assert_eq!(check(&stale, false), Vec::<String>::new());
+        assert_eq!(check(&stale, true).len(), 1);
+    }
+}
@@ -0,0 +1,12 @@
+use crate::client::Call;
+
+pub fn check(history: &[Call]) -> Vec<String> {
+    history
+        .iter()
+        .filter_map(|call| {
+            let res = call.ok()?;
+            let (sent, got) = (call.req.get("echo"), res.get("echo"));
+            (sent != got).then(|| format!("{} echoed {got:?} for {sent:?}", call.node))
+        })
+        .collect()
+}
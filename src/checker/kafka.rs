@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::client::{Call, Outcome};
+
+/// Checks that acknowledged offsets are never reused, that polls only return
+/// what was sent, in offset order, and that a poll does not skip over a send
+/// acknowledged before the poll began.
+pub fn check(history: &[Call]) -> Vec<String> {
+    let mut violations = vec![];
+
+    let mut acked: HashMap<(&str, u64), (u64, u64)> = HashMap::new();
+    let mut attempted: HashSet<(&str, u64)> = HashSet::new();
+    for call in history.iter().filter(|c| c.req_type() == "send") {
+        let (Some(key), Some(msg)) = (
+            call.req.get("key").and_then(Value::as_str),
+            call.req.get("msg").and_then(Value::as_u64),
+        ) else {
+            continue;
+        };
+        if call.outcome() != Outcome::Fail {
+            attempted.insert((key, msg));
+        }
+        let Some(offset) = call
+            .ok()
+            .and_then(|r| r.get("offset"))
+            .and_then(Value::as_u64)
+        else {
+            continue;
+        };
+        if let Some((other, _)) = acked.insert((key, offset), (msg, call.end)) {
+            violations.push(format!(
+                "Offset {offset} of {key} acknowledged for both {other} and {msg}"
+            ));
+        }
+    }
+
+    for call in history.iter().filter(|c| c.req_type() == "poll") {
+        let Some(msgs) = call
+            .ok()
+            .and_then(|r| r.get("msgs"))
+            .and_then(Value::as_object)
+        else {
+            continue;
+        };
+        for (key, log) in msgs {
+            let entries = log
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|e| Some((e.get(0)?.as_u64()?, e.get(1)?.as_u64()?)))
+                .collect::<Vec<_>>();
+
+            if entries.windows(2).any(|w| w[0].0 >= w[1].0) {
+                violations.push(format!("Poll of {key} on {} is out of order", call.node));
+            }
+            for &(offset, msg) in &entries {
+                match acked.get(&(key.as_str(), offset)) {
+                    Some(&(sent, _)) if sent != msg => violations.push(format!(
+                        "Poll of {key} returned {msg} at offset {offset}, but {sent} was acknowledged there"
+                    )),
+                    None if !attempted.contains(&(key.as_str(), msg)) => violations.push(format!(
+                        "Poll of {key} returned {msg} which was never sent"
+                    )),
+                    _ => {}
+                }
+            }
+
+            let from = call
+                .req
+                .get("offsets")
+                .and_then(|o| o.get(key))
+                .and_then(Value::as_u64)
+                .unwrap_or(0);
+            let Some(&(until, _)) = entries.last() else {
+                continue;
+            };
+            let returned = entries.iter().map(|e| e.0).collect::<HashSet<_>>();
+            let lost = acked
+                .iter()
+                .filter(|&(&(k, offset), &(_, end))| {
+                    k == key && end < call.start && (from..=until).contains(&offset)
+                })
+                .filter(|(&(_, offset), _)| !returned.contains(&offset))
+                .map(|(&(_, offset), &(msg, _))| (offset, msg))
+                .collect::<BTreeMap<_, _>>();
+            if !lost.is_empty() {
+                violations.push(format!(
+                    "Poll of {key} on {} skipped acknowledged sends {lost:?}",
+                    call.node
+                ));
+            }
+        }
+    }
+
+    violations
+}
@@ -0,0 +1,23 @@
+use crate::client::{Call, Opts, Workload};
+
+pub mod broadcast;
+pub mod counter;
+pub mod echo;
+pub mod kafka;
+pub mod set;
+pub mod txn;
+pub mod unique_ids;
+
+/// Checks the invariant of `opts.workload` over a client history, describing
+/// every violation found.
+pub fn check(opts: &Opts, history: &[Call]) -> Vec<String> {
+    match opts.workload {
+        Workload::Echo => echo::check(history),
+        Workload::UniqueIds => unique_ids::check(history),
+        Workload::Broadcast => broadcast::check(history),
+        Workload::GCounter | Workload::PnCounter => counter::check(history, opts.fresh_reads),
+        Workload::GSet => set::check(history),
+        Workload::Kafka => kafka::check(history),
+        Workload::TxnRwRegister => txn::check_calls(history, opts.consistency_model),
+    }
+}
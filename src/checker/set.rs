@@ -0,0 +1,59 @@
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+use crate::client::Call;
+
+/// Every acknowledged add must show up in every final read, and no read may
+/// return an element nobody tried to add. Elements are compared by their JSON
+/// text.
+pub fn check(history: &[Call]) -> Vec<String> {
+    let mut acked = BTreeSet::new();
+    let mut attempted = BTreeSet::new();
+    for call in history.iter().filter(|c| c.req_type() == "add") {
+        let Some(element) = call.req.get("element") else {
+            continue;
+        };
+        attempted.insert(element.to_string());
+        if call.ok().is_some() {
+            acked.insert(element.to_string());
+        }
+    }
+
+    let mut violations = vec![];
+    for call in history.iter().filter(|c| c.req_type() == "read") {
+        let Some(elements) = call.ok().map(read_elements) else {
+            if call.is_final {
+                violations.push(format!("Final read on {} did not complete", call.node));
+            }
+            continue;
+        };
+        let unexpected = elements.difference(&attempted).collect::<Vec<_>>();
+        if !unexpected.is_empty() {
+            violations.push(format!(
+                "{} read elements never added: {unexpected:?}",
+                call.node
+            ));
+        }
+        if call.is_final {
+            let lost = acked.difference(&elements).collect::<Vec<_>>();
+            if !lost.is_empty() {
+                violations.push(format!(
+                    "{} lost {} acknowledged elements: {lost:?}",
+                    call.node,
+                    lost.len()
+                ));
+            }
+        }
+    }
+    violations
+}
+
+fn read_elements(res: &Value) -> BTreeSet<String> {
+    res.get("value")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(Value::to_string)
+        .collect()
+}
@@ -0,0 +1,714 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    client::{Call, Outcome},
+    txn::{Op, OpType},
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Fail,
+    Info,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Txn {
+    pub process: u64,
+    pub status: Status,
+    pub txn: Vec<Op>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AnomalyKind {
+    G0,
+    G1a,
+    G1b,
+    G1c,
+    GSingle,
+    G2,
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnomalyKind::G0 => "G0",
+            AnomalyKind::G1a => "G1a",
+            AnomalyKind::G1b => "G1b",
+            AnomalyKind::G1c => "G1c",
+            AnomalyKind::GSingle => "G-single",
+            AnomalyKind::G2 => "G2",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsistencyModel {
+    ReadUncommitted,
+    ReadCommitted,
+    SnapshotIsolation,
+    Serializable,
+}
+
+impl ConsistencyModel {
+    pub fn prohibits(&self) -> &'static [AnomalyKind] {
+        use AnomalyKind::*;
+        match self {
+            ConsistencyModel::ReadUncommitted => &[G0],
+            ConsistencyModel::ReadCommitted => &[G0, G1a, G1b, G1c],
+            ConsistencyModel::SnapshotIsolation => &[G0, G1a, G1b, G1c, GSingle],
+            ConsistencyModel::Serializable => &[G0, G1a, G1b, G1c, GSingle, G2],
+        }
+    }
+}
+
+impl fmt::Display for ConsistencyModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConsistencyModel::ReadUncommitted => "read-uncommitted",
+            ConsistencyModel::ReadCommitted => "read-committed",
+            ConsistencyModel::SnapshotIsolation => "snapshot-isolation",
+            ConsistencyModel::Serializable => "serializable",
+        })
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Unknown consistency model: {0}")]
+#[diagnostic(code(checker::model))]
+pub struct UnknownModel(pub String);
+
+impl FromStr for ConsistencyModel {
+    type Err = UnknownModel;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-uncommitted" => Ok(ConsistencyModel::ReadUncommitted),
+            "read-committed" => Ok(ConsistencyModel::ReadCommitted),
+            "snapshot-isolation" => Ok(ConsistencyModel::SnapshotIsolation),
+            "serializable" => Ok(ConsistencyModel::Serializable),
+            _ => Err(UnknownModel(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dep {
+    Ww,
+    Wr,
+    Rw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub dep: Dep,
+    pub key: u64,
+}
+
+/// One hop of a dependency cycle: `edge` leads from `txn` to the next step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub txn: usize,
+    pub edge: Edge,
+}
+
+/// Transactions are identified by their index in the checked history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    AbortedRead {
+        reader: usize,
+        writer: usize,
+        key: u64,
+        val: u64,
+    },
+    IntermediateRead {
+        reader: usize,
+        writer: usize,
+        key: u64,
+        val: u64,
+    },
+    Cycle {
+        kind: AnomalyKind,
+        steps: Vec<Step>,
+    },
+}
+
+impl Anomaly {
+    pub fn kind(&self) -> AnomalyKind {
+        match self {
+            Anomaly::AbortedRead { .. } => AnomalyKind::G1a,
+            Anomaly::IntermediateRead { .. } => AnomalyKind::G1b,
+            Anomaly::Cycle { kind, .. } => *kind,
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::AbortedRead {
+                reader,
+                writer,
+                key,
+                val,
+            } => write!(
+                f,
+                "G1a: T{reader} read {key}={val} written by aborted T{writer}"
+            ),
+            Anomaly::IntermediateRead {
+                reader,
+                writer,
+                key,
+                val,
+            } => write!(
+                f,
+                "G1b: T{reader} read intermediate {key}={val} written by T{writer}"
+            ),
+            Anomaly::Cycle { kind, steps } => {
+                write!(f, "{kind}:")?;
+                for step in steps {
+                    let dep = match step.edge.dep {
+                        Dep::Ww => "ww",
+                        Dep::Wr => "wr",
+                        Dep::Rw => "rw",
+                    };
+                    write!(f, " T{} -{dep}({})->", step.txn, step.edge.key)?;
+                }
+                match steps.first() {
+                    Some(first) => write!(f, " T{}", first.txn),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Report {
+    pub fn kinds(&self) -> BTreeSet<AnomalyKind> {
+        self.anomalies.iter().map(Anomaly::kind).collect()
+    }
+
+    pub fn violations(&self, model: ConsistencyModel) -> impl Iterator<Item = &Anomaly> {
+        let prohibited = model.prohibits();
+        self.anomalies
+            .iter()
+            .filter(move |a| prohibited.contains(&a.kind()))
+    }
+
+    pub fn is_valid(&self, model: ConsistencyModel) -> bool {
+        self.violations(model).next().is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Deps {
+    ww: Option<u64>,
+    wr: Option<u64>,
+    rw: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Graph {
+    edges: BTreeMap<(usize, usize), Deps>,
+}
+
+type Adjacency = Vec<Vec<(usize, Edge)>>;
+
+impl Graph {
+    fn add(&mut self, from: usize, to: usize, dep: Dep, key: u64) {
+        if from == to {
+            return;
+        }
+        let deps = self.edges.entry((from, to)).or_default();
+        let slot = match dep {
+            Dep::Ww => &mut deps.ww,
+            Dep::Wr => &mut deps.wr,
+            Dep::Rw => &mut deps.rw,
+        };
+        slot.get_or_insert(key);
+    }
+
+    fn adjacency(&self, n: usize, allowed: &[Dep]) -> Adjacency {
+        let mut adj = vec![vec![]; n];
+        for (&(from, to), deps) in &self.edges {
+            for (dep, key) in [(Dep::Ww, deps.ww), (Dep::Wr, deps.wr), (Dep::Rw, deps.rw)] {
+                if let Some(key) = key.filter(|_| allowed.contains(&dep)) {
+                    adj[from].push((to, Edge { dep, key }));
+                }
+            }
+        }
+        adj
+    }
+}
+
+/// Builds the Adya dependency graph of `history` and searches it for anomalies.
+///
+/// Like Elle's rw-register analysis this assumes every write to a key is
+/// unique, and infers the version order from transactions that read a key
+/// and then overwrite it. Reads of `None` observe the initial state, which
+/// precedes every installed version.
+pub fn check(history: &[Txn]) -> Report {
+    let mut anomalies = vec![];
+
+    // (key, val) -> (writer, whether the write was the writer's final one)
+    let mut writes: HashMap<(u64, u64), (usize, bool)> = HashMap::new();
+    for (i, txn) in history.iter().enumerate() {
+        let finals = final_writes(&txn.txn);
+        for op in &txn.txn {
+            if let (OpType::Write, Some(val)) = (op.kind, op.val) {
+                writes
+                    .entry((op.key, val))
+                    .or_insert((i, finals.get(&op.key) == Some(&val)));
+            }
+        }
+    }
+
+    let mut graph = Graph::default();
+    let mut readers: HashMap<(u64, Option<u64>), Vec<usize>> = HashMap::new();
+    let mut successors: BTreeMap<(u64, Option<u64>), BTreeSet<u64>> = BTreeMap::new();
+    for (i, txn) in history.iter().enumerate() {
+        if txn.status != Status::Ok {
+            continue;
+        }
+        let reads = external_reads(&txn.txn);
+        for (&key, &read) in &reads {
+            readers.entry((key, read)).or_default().push(i);
+            let Some(val) = read else { continue };
+            match writes.get(&(key, val)) {
+                Some(&(writer, _)) if writer == i => {}
+                Some(&(writer, _)) if history[writer].status == Status::Fail => {
+                    anomalies.push(Anomaly::AbortedRead {
+                        reader: i,
+                        writer,
+                        key,
+                        val,
+                    })
+                }
+                Some(&(writer, false)) => anomalies.push(Anomaly::IntermediateRead {
+                    reader: i,
+                    writer,
+                    key,
+                    val,
+                }),
+                Some(&(writer, true)) => graph.add(writer, i, Dep::Wr, key),
+                None => {}
+            }
+        }
+        for (key, val) in final_writes(&txn.txn) {
+            if let Some(&read) = reads.get(&key) {
+                successors.entry((key, read)).or_default().insert(val);
+            }
+        }
+    }
+
+    for (&(key, from), tos) in &successors {
+        for &to in tos {
+            let (to_writer, _) = writes[&(key, to)];
+            if let Some(&(from_writer, _)) = from.and_then(|f| writes.get(&(key, f))) {
+                graph.add(from_writer, to_writer, Dep::Ww, key);
+            }
+            for &reader in readers.get(&(key, from)).into_iter().flatten() {
+                graph.add(reader, to_writer, Dep::Rw, key);
+            }
+        }
+    }
+
+    // Every installed version follows the initial state.
+    for (&(key, _), &(writer, is_final)) in &writes {
+        if !is_final || history[writer].status == Status::Fail {
+            continue;
+        }
+        for &reader in readers.get(&(key, None)).into_iter().flatten() {
+            graph.add(reader, writer, Dep::Rw, key);
+        }
+    }
+
+    anomalies.extend(find_cycles(&graph, history.len()));
+    Report { anomalies }
+}
+
+fn external_reads(ops: &[Op]) -> BTreeMap<u64, Option<u64>> {
+    let mut written = HashSet::new();
+    let mut reads = BTreeMap::new();
+    for op in ops {
+        match op.kind {
+            OpType::Read if !written.contains(&op.key) => {
+                reads.entry(op.key).or_insert(op.val);
+            }
+            OpType::Read => {}
+            OpType::Write => {
+                written.insert(op.key);
+            }
+        }
+    }
+    reads
+}
+
+fn final_writes(ops: &[Op]) -> BTreeMap<u64, u64> {
+    ops.iter()
+        .filter(|op| op.kind == OpType::Write)
+        .filter_map(|op| op.val.map(|val| (op.key, val)))
+        .collect()
+}
+
+fn find_cycles(graph: &Graph, n: usize) -> Vec<Anomaly> {
+    let mut anomalies = vec![];
+
+    let ww = graph.adjacency(n, &[Dep::Ww]);
+    for comp in components(&ww) {
+        if let Some(steps) = cycle_through(&ww, &ww, &comp, |_| true) {
+            anomalies.push(Anomaly::Cycle {
+                kind: AnomalyKind::G0,
+                steps,
+            });
+        }
+    }
+
+    let committed = graph.adjacency(n, &[Dep::Ww, Dep::Wr]);
+    for comp in components(&committed) {
+        if let Some(steps) = cycle_through(&committed, &committed, &comp, |e| e.dep == Dep::Wr) {
+            anomalies.push(Anomaly::Cycle {
+                kind: AnomalyKind::G1c,
+                steps,
+            });
+        }
+    }
+
+    let all = graph.adjacency(n, &[Dep::Ww, Dep::Wr, Dep::Rw]);
+    for comp in components(&all) {
+        let is_rw = |e: &Edge| e.dep == Dep::Rw;
+        if let Some(steps) = cycle_through(&all, &committed, &comp, is_rw) {
+            anomalies.push(Anomaly::Cycle {
+                kind: AnomalyKind::GSingle,
+                steps,
+            });
+        } else if let Some(steps) = cycle_through(&all, &all, &comp, is_rw) {
+            anomalies.push(Anomaly::Cycle {
+                kind: AnomalyKind::G2,
+                steps,
+            });
+        }
+    }
+
+    anomalies
+}
+
+/// Finds a cycle inside `comp` that starts with an edge of `first` matching
+/// `pick` and returns to its origin using only edges of `rest`.
+fn cycle_through(
+    first: &Adjacency,
+    rest: &Adjacency,
+    comp: &HashSet<usize>,
+    pick: impl Fn(&Edge) -> bool,
+) -> Option<Vec<Step>> {
+    let mut members = comp.iter().copied().collect::<Vec<_>>();
+    members.sort_unstable();
+    for &from in &members {
+        for &(to, edge) in &first[from] {
+            if !comp.contains(&to) || !pick(&edge) {
+                continue;
+            }
+            if let Some(path) = shortest_path(rest, to, from, comp) {
+                let mut steps = vec![Step { txn: from, edge }];
+                steps.extend(path);
+                return Some(steps);
+            }
+        }
+    }
+    None
+}
+
+fn shortest_path(
+    adj: &Adjacency,
+    from: usize,
+    to: usize,
+    within: &HashSet<usize>,
+) -> Option<Vec<Step>> {
+    let mut prev: HashMap<usize, Step> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+    while let Some(v) = queue.pop_front() {
+        for &(w, edge) in &adj[v] {
+            if w == from || prev.contains_key(&w) || !within.contains(&w) {
+                continue;
+            }
+            prev.insert(w, Step { txn: v, edge });
+            if w == to {
+                let mut path = vec![];
+                let mut cur = to;
+                while cur != from {
+                    let step = prev[&cur];
+                    path.push(step);
+                    cur = step.txn;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            queue.push_back(w);
+        }
+    }
+    None
+}
+
+/// Strongly connected components with more than one member (Kosaraju).
+fn components(adj: &Adjacency) -> Vec<HashSet<usize>> {
+    let n = adj.len();
+    let mut visited = vec![false; n];
+    let mut order = Vec::with_capacity(n);
+    for start in 0..n {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![(start, 0)];
+        while let Some(&(v, i)) = stack.last() {
+            match adj[v].get(i) {
+                Some(&(w, _)) => {
+                    let top = stack.len() - 1;
+                    stack[top].1 += 1;
+                    if !visited[w] {
+                        visited[w] = true;
+                        stack.push((w, 0));
+                    }
+                }
+                None => {
+                    order.push(v);
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    let mut rev = vec![vec![]; n];
+    for (v, out) in adj.iter().enumerate() {
+        for &(w, _) in out {
+            rev[w].push(v);
+        }
+    }
+
+    let mut assigned = vec![false; n];
+    let mut comps = vec![];
+    for &root in order.iter().rev() {
+        if assigned[root] {
+            continue;
+        }
+        assigned[root] = true;
+        let mut comp = HashSet::new();
+        let mut stack = vec![root];
+        while let Some(v) = stack.pop() {
+            comp.insert(v);
+            for &u in &rev[v] {
+                if !assigned[u] {
+                    assigned[u] = true;
+                    stack.push(u);
+                }
+            }
+        }
+        if comp.len() > 1 {
+            comps.push(comp);
+        }
+    }
+    comps
+}
+
+/// Checks a client history of `txn` requests under `model`. Indeterminate
+/// and failed requests contribute their requested operations.
+pub fn check_calls(history: &[Call], model: ConsistencyModel) -> Vec<String> {
+    let txns = history
+        .iter()
+        .filter_map(|call| {
+            let (status, ops) = match call.outcome() {
+                Outcome::Ok => (Status::Ok, call.res.as_ref()?.get("txn")?),
+                Outcome::Fail => (Status::Fail, call.req.get("txn")?),
+                Outcome::Info => (Status::Info, call.req.get("txn")?),
+            };
+            Some(Txn {
+                process: call.process,
+                status,
+                txn: Vec::<Op>::deserialize(ops).ok()?,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    check(&txns)
+        .violations(model)
+        .map(|a| format!("{a} (not {model})"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(key: u64, val: Option<u64>) -> Op {
+        Op {
+            kind: OpType::Read,
+            key,
+            val,
+        }
+    }
+
+    fn w(key: u64, val: u64) -> Op {
+        Op {
+            kind: OpType::Write,
+            key,
+            val: Some(val),
+        }
+    }
+
+    fn ok(txn: Vec<Op>) -> Txn {
+        Txn {
+            process: 0,
+            status: Status::Ok,
+            txn,
+        }
+    }
+
+    fn failed(txn: Vec<Op>) -> Txn {
+        Txn {
+            status: Status::Fail,
+            ..ok(txn)
+        }
+    }
+
+    /// Checks that `history` shows `kind` and is valid under `allowed` but
+    /// not under `prohibited`.
+    fn assert_anomaly(
+        history: &[Txn],
+        kind: AnomalyKind,
+        allowed: ConsistencyModel,
+        prohibited: ConsistencyModel,
+    ) {
+        let report = check(history);
+        assert!(report.kinds().contains(&kind), "{:?}", report.anomalies);
+        assert!(report.is_valid(allowed), "{:?}", report.anomalies);
+        assert!(!report.is_valid(prohibited), "{:?}", report.anomalies);
+    }
+
+    #[test]
+    fn serializable_history_is_clean() {
+        let history = [
+            ok(vec![w(1, 1)]),
+            ok(vec![r(1, Some(1)), w(1, 2)]),
+            ok(vec![r(1, Some(2)), r(2, None)]),
+            failed(vec![w(2, 1)]),
+        ];
+        let report = check(&history);
+        assert_eq!(report.anomalies, vec![]);
+        assert!(report.is_valid(ConsistencyModel::Serializable));
+    }
+
+    #[test]
+    fn g0_write_cycle() {
+        // x is installed as 1 then 2, y as 2 then 1.
+        let history = [
+            ok(vec![r(2, Some(2)), w(1, 1), w(2, 1)]),
+            ok(vec![r(1, Some(1)), w(1, 2), w(2, 2)]),
+        ];
+        let report = check(&history);
+        assert!(report.kinds().contains(&AnomalyKind::G0));
+        assert!(!report.is_valid(ConsistencyModel::ReadUncommitted));
+    }
+
+    #[test]
+    fn g1a_aborted_read() {
+        let history = [failed(vec![w(1, 1)]), ok(vec![r(1, Some(1))])];
+        assert_eq!(
+            check(&history).anomalies,
+            vec![Anomaly::AbortedRead {
+                reader: 1,
+                writer: 0,
+                key: 1,
+                val: 1
+            }]
+        );
+        assert_anomaly(
+            &history,
+            AnomalyKind::G1a,
+            ConsistencyModel::ReadUncommitted,
+            ConsistencyModel::ReadCommitted,
+        );
+    }
+
+    #[test]
+    fn g1b_intermediate_read() {
+        let history = [ok(vec![w(1, 1), w(1, 2)]), ok(vec![r(1, Some(1))])];
+        assert_eq!(
+            check(&history).anomalies,
+            vec![Anomaly::IntermediateRead {
+                reader: 1,
+                writer: 0,
+                key: 1,
+                val: 1
+            }]
+        );
+        assert_anomaly(
+            &history,
+            AnomalyKind::G1b,
+            ConsistencyModel::ReadUncommitted,
+            ConsistencyModel::ReadCommitted,
+        );
+    }
+
+    #[test]
+    fn g1c_circular_information_flow() {
+        let history = [
+            ok(vec![w(1, 1), r(2, Some(1))]),
+            ok(vec![w(2, 1), r(1, Some(1))]),
+        ];
+        assert_eq!(check(&history).kinds(), BTreeSet::from([AnomalyKind::G1c]));
+        assert_anomaly(
+            &history,
+            AnomalyKind::G1c,
+            ConsistencyModel::ReadUncommitted,
+            ConsistencyModel::ReadCommitted,
+        );
+    }
+
+    #[test]
+    fn g_single_read_skew() {
+        // The reader misses the write to 1 but sees the one to 2.
+        let history = [
+            ok(vec![r(1, None), r(2, Some(1))]),
+            ok(vec![w(1, 1), w(2, 1)]),
+        ];
+        assert_eq!(
+            check(&history).kinds(),
+            BTreeSet::from([AnomalyKind::GSingle])
+        );
+        assert_anomaly(
+            &history,
+            AnomalyKind::GSingle,
+            ConsistencyModel::ReadCommitted,
+            ConsistencyModel::SnapshotIsolation,
+        );
+    }
+
+    #[test]
+    fn g2_write_skew() {
+        let history = [
+            ok(vec![r(1, None), r(2, None), w(1, 1)]),
+            ok(vec![r(1, None), r(2, None), w(2, 1)]),
+        ];
+        assert_eq!(check(&history).kinds(), BTreeSet::from([AnomalyKind::G2]));
+        assert_anomaly(
+            &history,
+            AnomalyKind::G2,
+            ConsistencyModel::SnapshotIsolation,
+            ConsistencyModel::Serializable,
+        );
+    }
+}
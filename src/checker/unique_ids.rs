@@ -0,0 +1,17 @@
+use std::collections::HashMap;
+
+use crate::client::Call;
+
+pub fn check(history: &[Call]) -> Vec<String> {
+    let mut seen = HashMap::new();
+    let mut violations = vec![];
+    for call in history {
+        let Some(id) = call.ok().and_then(|res| res.get("id")) else {
+            continue;
+        };
+        if let Some(first) = seen.insert(id.to_string(), &call.node) {
+            violations.push(format!("Duplicate id {id} from {first} and {}", call.node));
+        }
+    }
+    violations
+}
@@ -0,0 +1,389 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Args;
+use compact_str::{format_compact, CompactString};
+use futures::future::try_join_all;
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::mpsc,
+    time::{interval_at, Instant, MissedTickBehavior},
+};
+use tracing::{debug, info};
+
+use crate::{
+    checker::txn::ConsistencyModel,
+    error::{NodeError, WithReason},
+    message::{Body, Message},
+    sim::{nemesis::Nemesis, Network},
+};
+
+pub use workload::{Generator, Shared, Workload};
+
+mod workload;
+
+#[derive(Args, Debug, Clone)]
+pub struct Opts {
+    #[arg(short, long, value_enum)]
+    pub workload: Workload,
+    #[arg(long, default_value_t = 1)]
+    pub node_count: usize,
+    /// Number of clients, either absolute or per node, e.g. `4` or `2n`.
+    #[arg(long, default_value = "1n")]
+    pub concurrency: Concurrency,
+    /// Total requests per second across all clients.
+    #[arg(long, default_value_t = 5.0, value_parser = parse_rate)]
+    pub rate: f64,
+    /// Seconds to generate requests for.
+    #[arg(long, default_value_t = 10)]
+    pub time_limit: u64,
+    /// Milliseconds every message spends in flight.
+    #[arg(long, default_value_t = 0)]
+    pub latency: u64,
+    /// Seconds to wait for a reply before recording the request as indeterminate.
+    #[arg(long, default_value_t = 5)]
+    pub timeout: u64,
+    /// Seconds to let the cluster settle before the final reads.
+    #[arg(long, default_value_t = 2)]
+    pub recovery: u64,
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Write the recorded history to this file as JSONL.
+    #[arg(long)]
+    pub history: Option<PathBuf>,
+    /// Faults to inject while the workload runs.
+    #[arg(long, value_enum)]
+    pub nemesis: Vec<Nemesis>,
+    /// Average seconds between faults.
+    #[arg(long, default_value_t = 10.0)]
+    pub nemesis_interval: f64,
+    /// Consistency model transactional histories are checked against.
+    #[arg(long, default_value = "read-uncommitted")]
+    pub consistency_model: ConsistencyModel,
+    /// Require counter reads to reflect every add acknowledged before they
+    /// began, rather than only final reads to be complete.
+    #[arg(long)]
+    pub fresh_reads: bool,
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        Ok(_) => Err(format!(
+            "Rate must be a positive number of requests per second: {s}"
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Concurrency {
+    Total(usize),
+    PerNode(usize),
+}
+
+impl Concurrency {
+    pub fn resolve(&self, node_count: usize) -> usize {
+        match *self {
+            Concurrency::Total(n) => n,
+            Concurrency::PerNode(n) => n * node_count,
+        }
+        .max(1)
+    }
+}
+
+impl fmt::Display for Concurrency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Concurrency::Total(n) => write!(f, "{n}"),
+            Concurrency::PerNode(n) => write!(f, "{n}n"),
+        }
+    }
+}
+
+impl FromStr for Concurrency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |_| format!("Invalid concurrency: {s}");
+        match s.strip_suffix('n') {
+            Some(n) => n.parse().map(Concurrency::PerNode).map_err(invalid),
+            None => s.parse().map(Concurrency::Total).map_err(invalid),
+        }
+    }
+}
+
+/// One completed (or abandoned) request. Times are microseconds since the
+/// start of the run, and a missing `res` means the request timed out.
+/// `is_final` marks the reads issued once the cluster has settled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Call {
+    pub process: u64,
+    pub node: CompactString,
+    pub start: u64,
+    pub end: u64,
+    pub req: Value,
+    pub res: Option<Value>,
+    #[serde(default)]
+    pub is_final: bool,
+}
+
+const INDEFINITE_TIMEOUT: u64 = 0;
+const INDEFINITE_CRASH: u64 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Fail,
+    Info,
+}
+
+impl Call {
+    /// Timeouts, and the indefinite Maelstrom errors `timeout` (0) and
+    /// `crash` (13), may or may not have taken effect.
+    pub fn outcome(&self) -> Outcome {
+        let Some(res) = &self.res else {
+            return Outcome::Info;
+        };
+        if res.get("type").and_then(Value::as_str) != Some("error") {
+            return Outcome::Ok;
+        }
+        match res.get("code").and_then(Value::as_u64) {
+            Some(INDEFINITE_TIMEOUT | INDEFINITE_CRASH) => Outcome::Info,
+            _ => Outcome::Fail,
+        }
+    }
+
+    pub fn req_type(&self) -> &str {
+        self.req.get("type").and_then(Value::as_str).unwrap_or("")
+    }
+
+    /// The response, if the request definitely succeeded.
+    pub fn ok(&self) -> Option<&Value> {
+        self.res.as_ref().filter(|_| self.outcome() == Outcome::Ok)
+    }
+}
+
+/// A client endpoint on the network with at most one request in flight.
+pub struct Client {
+    pub id: CompactString,
+    out: mpsc::Sender<Message<Value>>,
+    rx: mpsc::Receiver<Message<Value>>,
+    msg_id: u32,
+}
+
+impl Client {
+    pub fn new(net: &Network, id: impl Into<CompactString>) -> Self {
+        let id = id.into();
+        Self {
+            rx: net.register(id.clone()),
+            out: net.sender(),
+            id,
+            msg_id: 0,
+        }
+    }
+
+    pub async fn call(
+        &mut self,
+        node: &CompactString,
+        payload: Value,
+        timeout: Duration,
+    ) -> Result<Option<Value>, NodeError> {
+        self.msg_id += 1;
+        let msg_id = self.msg_id;
+        self.out
+            .send(Message {
+                src: self.id.clone(),
+                dst: node.clone(),
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload,
+                },
+            })
+            .await
+            .with_reason("Failed to send client request")?;
+
+        let reply = async {
+            while let Some(msg) = self.rx.recv().await {
+                if msg.body.in_reply_to == Some(msg_id) {
+                    return Some(msg.body.payload);
+                }
+                debug!(client = self.id.as_str(), ?msg, "Dropping stale reply");
+            }
+            None
+        };
+        Ok(tokio::time::timeout(timeout, reply).await.ok().flatten())
+    }
+}
+
+/// Drives `opts.workload` against `nodes` and returns the history ordered by
+/// start time.
+pub async fn run(
+    opts: &Opts,
+    net: &Network,
+    nodes: &[CompactString],
+    seed: u64,
+) -> Result<Vec<Call>, NodeError> {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(opts.timeout);
+    let since_start = move || start.elapsed().as_micros() as u64;
+
+    let mut setup = Client::new(net, "c0");
+    for (node, req) in opts.workload.setup(nodes) {
+        setup
+            .call(&node, req, timeout)
+            .await?
+            .with_reason(format_compact!("{node} did not answer setup request"))?;
+    }
+
+    let concurrency = opts.concurrency.resolve(nodes.len());
+    let period = Duration::from_secs_f64(concurrency as f64 / opts.rate);
+    let deadline = start + Duration::from_secs(opts.time_limit);
+    let shared = Shared::default();
+    info!(
+        workload = ?opts.workload,
+        concurrency,
+        rate = opts.rate,
+        seed,
+        "Starting clients"
+    );
+
+    let workers = (0..concurrency).map(|p| {
+        let mut client = Client::new(net, format_compact!("c{}", p + 1));
+        let node = nodes[p % nodes.len()].clone();
+        let mut gen = opts.workload.generator(&shared);
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(p as u64));
+        let first = start + period.mul_f64(p as f64 / concurrency as f64);
+
+        tokio::spawn(async move {
+            let mut calls = vec![];
+            let mut process = p as u64;
+            let mut ticks = interval_at(first, period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            while ticks.tick().await < deadline {
+                let req = gen.next(&mut rng);
+                let begin = since_start();
+                let res = client.call(&node, req.clone(), timeout).await?;
+                if let Some(res) = &res {
+                    gen.observe(&req, res);
+                }
+                let crashed = res.is_none();
+                calls.push(Call {
+                    process,
+                    node: node.clone(),
+                    start: begin,
+                    end: since_start(),
+                    req,
+                    res,
+                    is_final: false,
+                });
+                // A client that gave up on a request never reuses its process id.
+                if crashed {
+                    process += concurrency as u64;
+                }
+            }
+            Ok::<_, NodeError>(calls)
+        })
+    });
+    let mut history = try_join_all(workers)
+        .await
+        .map_err(|_| NodeError::new("Client task panicked"))?
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
+
+    let final_ops = opts.workload.final_ops(nodes);
+    if !final_ops.is_empty() {
+        tokio::time::sleep(Duration::from_secs(opts.recovery)).await;
+        let mut client = Client::new(net, format_compact!("c{}", concurrency + 1));
+        let process = history.iter().map(|c| c.process + 1).max().unwrap_or(0);
+        for (node, req) in final_ops {
+            let begin = since_start();
+            let res = client.call(&node, req.clone(), timeout).await?;
+            history.push(Call {
+                process,
+                node,
+                start: begin,
+                end: since_start(),
+                req,
+                res,
+                is_final: true,
+            });
+        }
+    }
+
+    history.sort_by_key(|c| c.start);
+    Ok(history)
+}
+
+pub fn write_history(path: &Path, history: &[Call]) -> Result<(), NodeError> {
+    let mut out = BufWriter::new(File::create(path).with_reason("Failed to create history")?);
+    for call in history {
+        serde_json::to_writer(&mut out, call).with_reason("Failed to serialize history")?;
+        writeln!(out).with_reason("Failed to write history")?;
+    }
+    out.flush().with_reason("Failed to write history")
+}
+
+pub fn summarize(history: &[Call]) {
+    let count = |o| history.iter().filter(|c| c.outcome() == o).count();
+    println!(
+        "{} requests: {} ok, {} failed, {} indeterminate",
+        history.len(),
+        count(Outcome::Ok),
+        count(Outcome::Fail),
+        count(Outcome::Info)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        opts: Opts,
+    }
+
+    #[test]
+    fn indefinite_errors_are_info() {
+        let call = |res| Call {
+            process: 0,
+            node: "n0".into(),
+            start: 0,
+            end: 1,
+            req: json!({"type": "add", "delta": 1}),
+            res,
+            is_final: false,
+        };
+        let error = |code| Some(json!({"type": "error", "code": code, "text": ""}));
+        assert_eq!(call(None).outcome(), Outcome::Info);
+        assert_eq!(call(Some(json!({"type": "add_ok"}))).outcome(), Outcome::Ok);
+        assert_eq!(call(error(0)).outcome(), Outcome::Info);
+        assert_eq!(call(error(13)).outcome(), Outcome::Info);
+        assert_eq!(call(error(20)).outcome(), Outcome::Fail);
+        assert_eq!(call(error(22)).outcome(), Outcome::Fail);
+    }
+
+    #[test]
+    fn rate_must_be_positive() {
+        let parse = |rate| Cli::try_parse_from(["client", "-w", "echo", "--rate", rate]);
+        assert_eq!(parse("2.5").unwrap().opts.rate, 2.5);
+        for rate in ["0", "-1", "inf", "NaN", "fast"] {
+            assert!(parse(rate).is_err(), "accepted rate {rate}");
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use clap::ValueEnum;
+use compact_str::{format_compact, CompactString};
+use rand::{rngs::StdRng, Rng};
+use serde_json::{json, Value};
+
+use crate::txn::{Op, OpType};
+
+const KAFKA_KEYS: u64 = 8;
+const TXN_KEYS: u64 = 8;
+const TXN_MAX_LEN: usize = 4;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    Broadcast,
+    GCounter,
+    PnCounter,
+    GSet,
+    Kafka,
+    TxnRwRegister,
+}
+
+/// State shared by every client of a run, used to keep generated values unique.
+#[derive(Debug, Clone, Default)]
+pub struct Shared {
+    counter: Arc<AtomicU64>,
+}
+
+impl Shared {
+    fn next(&self) -> u64 {
+        self.counter.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+pub trait Generator: Send {
+    fn next(&mut self, rng: &mut StdRng) -> Value;
+
+    fn observe(&mut self, _req: &Value, _res: &Value) {}
+}
+
+impl Workload {
+    /// Requests sent once before any client starts, such as `topology`.
+    pub fn setup(&self, nodes: &[CompactString]) -> Vec<(CompactString, Value)> {
+        match self {
+            Workload::Broadcast => {
+                let topology = grid(nodes);
+                nodes
+                    .iter()
+                    .map(|n| (n.clone(), json!({"type": "topology", "topology": topology})))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Reads issued against every node once the cluster has settled.
+    pub fn final_ops(&self, nodes: &[CompactString]) -> Vec<(CompactString, Value)> {
+        match self {
+            Workload::Broadcast | Workload::GCounter | Workload::PnCounter | Workload::GSet => {
+                nodes
+                    .iter()
+                    .map(|n| (n.clone(), json!({"type": "read"})))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn generator(&self, shared: &Shared) -> Box<dyn Generator> {
+        let shared = shared.clone();
+        match self {
+            Workload::Echo => Box::new(Echo(shared)),
+            Workload::UniqueIds => Box::new(UniqueIds),
+            Workload::Broadcast => Box::new(Broadcast(shared)),
+            Workload::GCounter => Box::new(GCounter),
+            Workload::PnCounter => Box::new(PnCounter),
+            Workload::GSet => Box::new(GSet(shared)),
+            Workload::Kafka => Box::new(Kafka {
+                shared,
+                next: BTreeMap::new(),
+                polled: BTreeMap::new(),
+            }),
+            Workload::TxnRwRegister => Box::new(TxnRwRegister(shared)),
+        }
+    }
+}
+
+/// Maelstrom's default topology: nodes laid out row by row on a square grid,
+/// each connected to its horizontal and vertical neighbours.
+pub fn grid(nodes: &[CompactString]) -> HashMap<CompactString, Vec<CompactString>> {
+    let width = (nodes.len() as f64).sqrt().ceil().max(1.0) as usize;
+    nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let mut neighbours = vec![];
+            if i % width > 0 {
+                neighbours.push(nodes[i - 1].clone());
+            }
+            if i % width + 1 < width && i + 1 < nodes.len() {
+                neighbours.push(nodes[i + 1].clone());
+            }
+            if i >= width {
+                neighbours.push(nodes[i - width].clone());
+            }
+            if i + width < nodes.len() {
+                neighbours.push(nodes[i + width].clone());
+            }
+            (node.clone(), neighbours)
+        })
+        .collect()
+}
+
+struct Echo(Shared);
+
+impl Generator for Echo {
+    fn next(&mut self, _rng: &mut StdRng) -> Value {
+        json!({"type": "echo", "echo": format!("Please echo {}", self.0.next())})
+    }
+}
+
+struct UniqueIds;
+
+impl Generator for UniqueIds {
+    fn next(&mut self, _rng: &mut StdRng) -> Value {
+        json!({"type": "generate"})
+    }
+}
+
+struct Broadcast(Shared);
+
+impl Generator for Broadcast {
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        if rng.gen_bool(0.5) {
+            json!({"type": "broadcast", "message": self.0.next()})
+        } else {
+            json!({"type": "read"})
+        }
+    }
+}
+
+struct GCounter;
+
+impl Generator for GCounter {
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        if rng.gen_bool(0.5) {
+            json!({"type": "add", "delta": rng.gen_range(0..5)})
+        } else {
+            json!({"type": "read"})
+        }
+    }
+}
+
+struct PnCounter;
+
+impl Generator for PnCounter {
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        if rng.gen_bool(0.5) {
+            json!({"type": "add", "delta": rng.gen_range(-5..5)})
+        } else {
+            json!({"type": "read"})
+        }
+    }
+}
+
+struct GSet(Shared);
+
+impl Generator for GSet {
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        if rng.gen_bool(0.5) {
+            let n = self.0.next();
+            let element = match n % 3 {
+                0 => json!(n),
+                1 => json!(format!("e{n}")),
+                _ => json!({"id": n}),
+            };
+            json!({"type": "add", "element": element})
+        } else {
+            json!({"type": "read"})
+        }
+    }
+}
+
+struct Kafka {
+    shared: Shared,
+    /// Next offset to poll from, per key.
+    next: BTreeMap<CompactString, u64>,
+    /// Highest offset seen by a poll, per key.
+    polled: BTreeMap<CompactString, u64>,
+}
+
+impl Generator for Kafka {
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        match rng.gen_range(0..10) {
+            0..=4 => json!({
+                "type": "send",
+                "key": format_compact!("{}", rng.gen_range(0..KAFKA_KEYS)),
+                "msg": self.shared.next(),
+            }),
+            5..=7 => {
+                let offsets = (0..KAFKA_KEYS)
+                    .map(|k| {
+                        let key = format_compact!("{k}");
+                        let offset = self.next.get(&key).copied().unwrap_or(0);
+                        (key, offset)
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                json!({"type": "poll", "offsets": offsets})
+            }
+            8 if !self.polled.is_empty() => {
+                json!({"type": "commit_offsets", "offsets": self.polled})
+            }
+            _ => {
+                let keys = (0..KAFKA_KEYS)
+                    .map(|k| format_compact!("{k}"))
+                    .collect::<Vec<_>>();
+                json!({"type": "list_committed_offsets", "keys": keys})
+            }
+        }
+    }
+
+    fn observe(&mut self, _req: &Value, res: &Value) {
+        let Some(msgs) = res.get("msgs").and_then(Value::as_object) else {
+            return;
+        };
+        for (key, log) in msgs {
+            let last = log
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.get(0).and_then(Value::as_u64))
+                .max();
+            if let Some(last) = last {
+                let key = CompactString::from(key.as_str());
+                self.next.insert(key.clone(), last + 1);
+                self.polled.insert(key, last);
+            }
+        }
+    }
+}
+
+struct TxnRwRegister(Shared);
+
+impl Generator for TxnRwRegister {
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        let txn = (0..rng.gen_range(1..=TXN_MAX_LEN))
+            .map(|_| {
+                let key = rng.gen_range(0..TXN_KEYS);
+                if rng.gen_bool(0.5) {
+                    Op {
+                        kind: OpType::Read,
+                        key,
+                        val: None,
+                    }
+                } else {
+                    Op {
+                        kind: OpType::Write,
+                        key,
+                        val: Some(self.0.next()),
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        json!({"type": "txn", "txn": txn})
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+
+use super::Crdt;
+
+/// A grow-only counter: every node only ever increments its own entry, and
+/// replicas merge by taking the maximum of each entry.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct GCounter {
+    counts: HashMap<CompactString, u64>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, node: &str, delta: u64) {
+        if delta > 0 {
+            *self.counts.entry(node.into()).or_default() += delta;
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// The part of the total contributed by `node`.
+    pub fn get(&self, node: &str) -> u64 {
+        self.counts.get(node).copied().unwrap_or(0)
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (node, &count) in &other.counts {
+            let entry = self.counts.entry(node.clone()).or_default();
+            if count > *entry {
+                *entry = count;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let counts = self
+            .counts
+            .iter()
+            .filter(|(node, &count)| count > since.get(node))
+            .map(|(node, &count)| (node.clone(), count))
+            .collect();
+        Self { counts }
+    }
+}
+
+/// A counter that can also go down, kept as one G-Counter of increments and
+/// one of decrements.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PNCounter {
+    inc: GCounter,
+    dec: GCounter,
+}
+
+impl PNCounter {
+    pub fn add(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.inc.increment(node, delta.unsigned_abs());
+        } else {
+            self.dec.increment(node, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.inc.value() as i64 - self.dec.value() as i64
+    }
+}
+
+impl Crdt for PNCounter {
+    fn merge(&mut self, other: &Self) -> bool {
+        let inc = self.inc.merge(&other.inc);
+        self.dec.merge(&other.dec) || inc
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            inc: self.inc.delta(&since.inc),
+            dec: self.dec.delta(&since.dec),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Crdt, Element, LWWRegister};
+
+/// A map of last-writer-wins registers. Removing a key leaves an empty
+/// register behind, so the removal can outlast older writes to it.
+///
+/// Keys must serialize as JSON object keys, such as strings or integers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent, bound = "K: Element, V: Element")]
+pub struct LWWMap<K, V> {
+    entries: BTreeMap<K, LWWRegister<V>>,
+}
+
+impl<K, V> Default for LWWMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Element, V: Element> LWWMap<K, V> {
+    pub fn insert(&mut self, node: &str, key: K, value: V) {
+        self.entries.entry(key).or_default().set(node, value);
+    }
+
+    pub fn remove(&mut self, node: &str, key: &K) {
+        if let Some(reg) = self.entries.get_mut(key) {
+            reg.clear(node);
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.get()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, reg)| Some((key, reg.get()?)))
+    }
+}
+
+impl<K: Element, V: Element> Crdt for LWWMap<K, V> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (key, reg) in &other.entries {
+            changed |= self.entries.entry(key.clone()).or_default().merge(reg);
+        }
+        changed
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(key, reg)| {
+                let delta = match since.entries.get(key) {
+                    Some(old) => reg.delta(old),
+                    None => reg.clone(),
+                };
+                (delta != LWWRegister::default()).then(|| (key.clone(), delta))
+            })
+            .collect();
+        Self { entries }
+    }
+}
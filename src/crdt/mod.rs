@@ -0,0 +1,361 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use compact_str::CompactString;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, instrument};
+
+use crate::{
+    error::{JsonDeError, NodeError},
+    message::Message,
+    node::Node,
+};
+
+pub use counter::{GCounter, PNCounter};
+pub use map::LWWMap;
+pub use register::{LWWRegister, MVRegister, Stamp};
+pub use set::{Element, GSet, Json, ORSet, TwoPSet};
+
+mod counter;
+mod map;
+mod register;
+mod set;
+
+const GOSSIP_PERIOD: Duration = Duration::from_millis(500);
+/// Every this many periods the whole state is sent instead of a delta.
+const FULL_SYNC_ROUNDS: u64 = 10;
+
+/// A state-based replicated data type. Merging must be commutative,
+/// associative and idempotent, so replicas converge however states are
+/// exchanged, and the default value must be the empty state.
+pub trait Crdt:
+    Serialize + DeserializeOwned + Clone + Default + PartialEq + fmt::Debug + Send + Sync + 'static
+{
+    /// Merges another replica's state, returning whether anything changed.
+    fn merge(&mut self, other: &Self) -> bool;
+
+    /// The part of this state that `since` lacks: merging it into `since`
+    /// gives the same result as merging the whole state.
+    fn delta(&self, since: &Self) -> Self;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Gossip<T> {
+    CrdtGossip { state: T, round: u64 },
+    CrdtGossipOk { round: u64 },
+}
+
+impl Gossip<Value> {
+    /// Whether `msg` carries replica state and should go to [`Replica::handle`].
+    pub fn matches(msg: &Message<Value>) -> bool {
+        msg.body
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .is_some_and(|t| t.starts_with("crdt_"))
+    }
+}
+
+/// What a peer is known to have, and the updates sent to it that it has not
+/// acknowledged yet, by round.
+struct Peer<T> {
+    known: T,
+    unconfirmed: BTreeMap<u64, T>,
+}
+
+impl<T: Crdt> Default for Peer<T> {
+    fn default() -> Self {
+        Self {
+            known: T::default(),
+            unconfirmed: BTreeMap::new(),
+        }
+    }
+}
+
+/// One node's copy of a CRDT, gossiped to every other node. Each period a
+/// peer is sent whatever it is not yet known to have, and every
+/// `FULL_SYNC_ROUNDS` periods the whole state. Gossip is not retried, but a
+/// peer only counts as having an update once it acknowledges it, so an update
+/// lost to a partition is part of every delta until one gets through.
+pub struct Replica<T> {
+    node: Arc<Node>,
+    state: Mutex<T>,
+    peers: Mutex<HashMap<CompactString, Peer<T>>>,
+}
+
+impl<T: Crdt> Replica<T> {
+    pub fn new(node: Arc<Node>) -> Arc<Self> {
+        Arc::new(Self {
+            node,
+            state: Mutex::new(T::default()),
+            peers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.state.lock())
+    }
+
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.state.lock())
+    }
+
+    #[instrument("Replica", skip_all, fields(src = msg.src.as_str()))]
+    pub async fn handle(&self, msg: &Message<Value>) -> Result<(), NodeError> {
+        match Gossip::<T>::de(&msg.body.payload)? {
+            Gossip::CrdtGossip { state, round } => {
+                if self.state.lock().merge(&state) {
+                    debug!("Merged gossip");
+                }
+                self.peers
+                    .lock()
+                    .entry(msg.src.clone())
+                    .or_default()
+                    .known
+                    .merge(&state);
+                self.node
+                    .send(msg.src.clone(), Gossip::<T>::CrdtGossipOk { round })
+                    .await
+            }
+            Gossip::CrdtGossipOk { round } => {
+                let mut peers = self.peers.lock();
+                let peer = peers.entry(msg.src.clone()).or_default();
+                if let Some(update) = peer.unconfirmed.remove(&round) {
+                    peer.known.merge(&update);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Gossips the state until the node stops.
+    pub async fn run(self: Arc<Self>) -> Result<(), NodeError> {
+        for round in 1u64.. {
+            tokio::time::sleep(GOSSIP_PERIOD).await;
+            let state = self.state.lock().clone();
+            let updates = {
+                let mut peers = self.peers.lock();
+                self.node
+                    .node_ids
+                    .iter()
+                    .filter(|n| **n != self.node.id)
+                    .filter_map(|id| {
+                        let peer = peers.entry(id.clone()).or_default();
+                        // Anything older is covered by a later update anyway.
+                        peer.unconfirmed = peer
+                            .unconfirmed
+                            .split_off(&round.saturating_sub(FULL_SYNC_ROUNDS));
+                        let update = if round % FULL_SYNC_ROUNDS == 0 {
+                            state.clone()
+                        } else {
+                            state.delta(&peer.known)
+                        };
+                        if update == T::default() {
+                            return None;
+                        }
+                        peer.unconfirmed.insert(round, update.clone());
+                        Some((id.clone(), update))
+                    })
+                    .collect::<Vec<_>>()
+            };
+            for (peer, state) in updates {
+                self.node
+                    .send(peer, Gossip::CrdtGossip { state, round })
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Replica<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Replica").field(&self.state.lock()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        error::JsonSerError,
+        message::{Body, Init},
+    };
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+    const TRIALS: usize = 500;
+
+    fn merged<T: Crdt>(a: &T, b: &T) -> T {
+        let mut a = a.clone();
+        a.merge(b);
+        a
+    }
+
+    /// Three replicas after random local operations and random full or delta
+    /// merges between them, so every state is one replicas can actually reach.
+    fn replicas<T: Crdt>(rng: &mut StdRng, op: &impl Fn(&mut T, &str, &mut StdRng)) -> [T; 3] {
+        let mut replicas = <[T; 3]>::default();
+        for _ in 0..rng.gen_range(0..30) {
+            let (i, j) = (rng.gen_range(0..3), rng.gen_range(0..3));
+            match rng.gen_range(0..4) {
+                0 => {
+                    let other = replicas[j].clone();
+                    replicas[i].merge(&other);
+                }
+                1 => {
+                    let delta = replicas[j].delta(&replicas[i]);
+                    replicas[i].merge(&delta);
+                }
+                _ => op(&mut replicas[i], NODES[i], rng),
+            }
+        }
+        replicas
+    }
+
+    fn check_laws<T: Crdt>(op: impl Fn(&mut T, &str, &mut StdRng)) {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..TRIALS {
+            let [a, b, c] = replicas(&mut rng, &op);
+            assert_eq!(merged(&a, &b), merged(&b, &a), "commutativity");
+            assert_eq!(
+                merged(&merged(&a, &b), &c),
+                merged(&a, &merged(&b, &c)),
+                "associativity"
+            );
+            assert_eq!(merged(&a, &a), a, "idempotence");
+            assert_eq!(merged(&a, &b.delta(&a)), merged(&a, &b), "delta");
+            let mut ab = a.clone();
+            assert_eq!(ab.merge(&b), ab != a, "merge reports changes");
+        }
+    }
+
+    #[test]
+    fn g_counter() {
+        check_laws(|c: &mut GCounter, node, rng| c.increment(node, rng.gen_range(0..3)));
+    }
+
+    #[test]
+    fn pn_counter() {
+        check_laws(|c: &mut PNCounter, node, rng| c.add(node, rng.gen_range(-2..3)));
+    }
+
+    #[test]
+    fn g_set() {
+        check_laws(|s: &mut GSet<u8>, _, rng| {
+            s.insert(rng.gen_range(0..5));
+        });
+    }
+
+    #[test]
+    fn two_p_set() {
+        check_laws(|s: &mut TwoPSet<u8>, _, rng| {
+            let elem = rng.gen_range(0..5);
+            if rng.gen() {
+                s.insert(elem);
+            } else {
+                s.remove(&elem);
+            }
+        });
+    }
+
+    #[test]
+    fn or_set() {
+        check_laws(|s: &mut ORSet<u8>, node, rng| {
+            let elem = rng.gen_range(0..5);
+            if rng.gen() {
+                s.insert(node, elem);
+            } else {
+                s.remove(&elem);
+            }
+        });
+    }
+
+    #[test]
+    fn lww_register() {
+        check_laws(|r: &mut LWWRegister<u8>, node, rng| {
+            if rng.gen_bool(0.8) {
+                r.set(node, rng.gen_range(0..5));
+            } else {
+                r.clear(node);
+            }
+        });
+    }
+
+    #[test]
+    fn mv_register() {
+        check_laws(|r: &mut MVRegister<u8>, node, rng| r.set(node, rng.gen_range(0..5)));
+    }
+
+    #[test]
+    fn lww_map() {
+        check_laws(|m: &mut LWWMap<u8, u8>, node, rng| {
+            let key = rng.gen_range(0..4);
+            if rng.gen_bool(0.8) {
+                m.insert(node, key, rng.gen_range(0..5));
+            } else {
+                m.remove(node, &key);
+            }
+        });
+    }
+
+    fn set(elems: &[u8]) -> GSet<u8> {
+        let mut set = GSet::default();
+        for elem in elems {
+            set.insert(*elem);
+        }
+        set
+    }
+
+    async fn sent(rx: &mut mpsc::Receiver<Message<Value>>) -> (GSet<u8>, u64) {
+        let msg = rx.recv().await.unwrap();
+        match Gossip::de(&msg.body.payload).unwrap() {
+            Gossip::CrdtGossip { state, round } => (state, round),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn updates_are_resent_until_acknowledged() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let init = Init {
+            node_id: "n0".into(),
+            node_ids: vec!["n0".into(), "n1".into()],
+        };
+        let replica = Replica::<GSet<u8>>::new(Arc::new(Node::from_init(init, tx)));
+        tokio::spawn(replica.clone().run());
+
+        replica.update(|s| s.insert(1));
+        assert_eq!(sent(&mut rx).await, (set(&[1]), 1));
+        replica.update(|s| s.insert(2));
+        assert_eq!(
+            sent(&mut rx).await,
+            (set(&[1, 2]), 2),
+            "round 1 was never acked"
+        );
+
+        let ack = Message {
+            src: "n1".into(),
+            dst: "n0".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: Gossip::<GSet<u8>>::CrdtGossipOk { round: 2 }
+                    .ser_val()
+                    .unwrap(),
+            },
+        };
+        replica.handle(&ack).await.unwrap();
+        replica.update(|s| s.insert(3));
+        assert_eq!(sent(&mut rx).await, (set(&[3]), 3));
+    }
+}
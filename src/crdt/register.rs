@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+
+use super::{Crdt, Element};
+
+/// A Lamport timestamp, with the writing node breaking ties.
+pub type Stamp = (u64, CompactString);
+
+/// A register where the write with the highest stamp wins. Stamps are Lamport
+/// clocks rather than wall clocks, so a write always beats every write its
+/// replica had seen.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(bound = "T: Element")]
+pub struct LWWRegister<T> {
+    value: Option<T>,
+    stamp: Stamp,
+}
+
+impl<T> Default for LWWRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            stamp: Stamp::default(),
+        }
+    }
+}
+
+impl<T: Element> LWWRegister<T> {
+    pub fn set(&mut self, node: &str, value: T) {
+        self.write(node, Some(value));
+    }
+
+    /// Empties the register, which wins over older writes like any other.
+    pub fn clear(&mut self, node: &str) {
+        self.write(node, None);
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn stamp(&self) -> &Stamp {
+        &self.stamp
+    }
+
+    fn write(&mut self, node: &str, value: Option<T>) {
+        self.stamp = (self.stamp.0 + 1, node.into());
+        self.value = value;
+    }
+}
+
+impl<T: Element> Crdt for LWWRegister<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        if other.stamp > self.stamp {
+            self.clone_from(other);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        if self.stamp > since.stamp {
+            self.clone()
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// A version vector: how many writes from each node a value has seen.
+type Version = BTreeMap<CompactString, u64>;
+
+fn dominated(a: &Version, b: &Version) -> bool {
+    a != b
+        && a.iter()
+            .all(|(node, n)| b.get(node).is_some_and(|m| n <= m))
+}
+
+/// A multi-value register: a write replaces every value its replica had seen,
+/// and concurrent writes are all kept until a later write replaces them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(bound = "T: Element")]
+pub struct MVRegister<T> {
+    values: Vec<(Version, T)>,
+}
+
+impl<T> Default for MVRegister<T> {
+    fn default() -> Self {
+        Self { values: vec![] }
+    }
+}
+
+impl<T: Element> MVRegister<T> {
+    pub fn set(&mut self, node: &str, value: T) {
+        let mut version = Version::new();
+        for (v, _) in &self.values {
+            for (n, &count) in v {
+                let entry = version.entry(n.clone()).or_default();
+                *entry = count.max(*entry);
+            }
+        }
+        *version.entry(node.into()).or_default() += 1;
+        self.values = vec![(version, value)];
+    }
+
+    /// Every value written concurrently by the latest writes.
+    pub fn get(&self) -> impl Iterator<Item = &T> {
+        self.values.iter().map(|(_, value)| value)
+    }
+}
+
+impl<T: Element> Crdt for MVRegister<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut all = self.values.clone();
+        all.extend(
+            other
+                .values
+                .iter()
+                .filter(|(v, _)| !self.values.iter().any(|(w, _)| w == v))
+                .cloned(),
+        );
+        let mut values = all
+            .iter()
+            .filter(|(v, _)| !all.iter().any(|(w, _)| dominated(v, w)))
+            .cloned()
+            .collect::<Vec<_>>();
+        values.sort();
+        let changed = values != self.values;
+        self.values = values;
+        changed
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let values = self
+            .values
+            .iter()
+            .filter(|(v, _)| !since.values.iter().any(|(w, _)| w == v))
+            .cloned()
+            .collect();
+        Self { values }
+    }
+}
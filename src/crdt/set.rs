@@ -0,0 +1,224 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fmt,
+    ops::Bound::{Included, Unbounded},
+};
+
+use compact_str::CompactString;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{Crdt, GCounter};
+
+/// What set elements need to be replicated.
+pub trait Element:
+    Ord + Clone + Serialize + DeserializeOwned + fmt::Debug + Send + Sync + 'static
+{
+}
+
+impl<T> Element for T where
+    T: Ord + Clone + Serialize + DeserializeOwned + fmt::Debug + Send + Sync + 'static
+{
+}
+
+/// An arbitrary JSON value usable as a set element, ordered by its JSON text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Json(pub Value);
+
+impl Ord for Json {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.to_string().cmp(&other.0.to_string())
+    }
+}
+
+impl PartialOrd for Json {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A grow-only set, merged by union.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent, bound = "T: Element")]
+pub struct GSet<T> {
+    elems: BTreeSet<T>,
+}
+
+impl<T> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elems: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Element> GSet<T> {
+    /// Adds an element, returning whether it was new.
+    pub fn insert(&mut self, elem: T) -> bool {
+        self.elems.insert(elem)
+    }
+
+    pub fn contains(&self, elem: &T) -> bool {
+        self.elems.contains(elem)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elems.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+}
+
+impl<T: Element> Crdt for GSet<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let len = self.elems.len();
+        self.elems.extend(other.elems.iter().cloned());
+        self.elems.len() != len
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            elems: self.elems.difference(&since.elems).cloned().collect(),
+        }
+    }
+}
+
+/// A set whose elements can be removed once, after which they can never be
+/// added back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(bound = "T: Element")]
+pub struct TwoPSet<T> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T> Default for TwoPSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Element> TwoPSet<T> {
+    pub fn insert(&mut self, elem: T) -> bool {
+        !self.removed.contains(&elem) && self.added.insert(elem)
+    }
+
+    /// Removes an element for good, returning whether it was present.
+    pub fn remove(&mut self, elem: &T) -> bool {
+        self.contains(elem) && self.removed.insert(elem.clone())
+    }
+
+    pub fn contains(&self, elem: &T) -> bool {
+        self.added.contains(elem) && !self.removed.contains(elem)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added.iter().filter(|e| !self.removed.contains(e))
+    }
+}
+
+impl<T: Element> Crdt for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let added = self.added.merge(&other.added);
+        self.removed.merge(&other.removed) || added
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            added: self.added.delta(&since.added),
+            removed: self.removed.delta(&since.removed),
+        }
+    }
+}
+
+/// Identifies one insertion: the node that made it and how many insertions
+/// that node had made by then.
+type Dot = (CompactString, u64);
+
+/// An observed-remove set: a removal only cancels the insertions its replica
+/// had seen, so an element added concurrently with its removal stays, and
+/// removed elements can be added again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(bound = "T: Element")]
+pub struct ORSet<T> {
+    adds: BTreeSet<(T, Dot)>,
+    removes: BTreeSet<(T, Dot)>,
+    clock: GCounter,
+}
+
+impl<T> Default for ORSet<T> {
+    fn default() -> Self {
+        Self {
+            adds: BTreeSet::new(),
+            removes: BTreeSet::new(),
+            clock: GCounter::default(),
+        }
+    }
+}
+
+impl<T: Element> ORSet<T> {
+    pub fn insert(&mut self, node: &str, elem: T) {
+        self.clock.increment(node, 1);
+        self.adds
+            .insert((elem, (node.into(), self.clock.get(node))));
+    }
+
+    /// Removes every insertion of `elem` seen so far, returning whether it was
+    /// present.
+    pub fn remove(&mut self, elem: &T) -> bool {
+        let live = self.live(elem).cloned().collect::<Vec<_>>();
+        let present = !live.is_empty();
+        self.removes.extend(live);
+        present
+    }
+
+    pub fn contains(&self, elem: &T) -> bool {
+        self.live(elem).next().is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut last = None;
+        self.adds
+            .iter()
+            .filter(|add| !self.removes.contains(add))
+            .map(|(elem, _)| elem)
+            .filter(move |&elem| last.replace(elem) != Some(elem))
+    }
+
+    fn live<'a>(&'a self, elem: &'a T) -> impl Iterator<Item = &'a (T, Dot)> {
+        let start = (elem.clone(), Dot::default());
+        self.adds
+            .range((Included(start), Unbounded))
+            .take_while(move |(e, _)| e == elem)
+            .filter(|add| !self.removes.contains(*add))
+    }
+}
+
+impl<T: Element> Crdt for ORSet<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let len = self.adds.len() + self.removes.len();
+        self.adds.extend(other.adds.iter().cloned());
+        self.removes.extend(other.removes.iter().cloned());
+        let clock = self.clock.merge(&other.clock);
+        self.adds.len() + self.removes.len() != len || clock
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            adds: self.adds.difference(&since.adds).cloned().collect(),
+            removes: self.removes.difference(&since.removes).cloned().collect(),
+            clock: self.clock.delta(&since.clock),
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use compact_str::CompactString;
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+/// Round trips kept per peer.
+const WINDOW: usize = 100;
+/// Assumed round trip, and retransmission timeout, before any round trip has
+/// been measured.
+const INITIAL_RTT: Duration = Duration::from_millis(300);
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(2);
+/// Timer granularity the RTO leaves room for when the RTT barely varies.
+const GRANULARITY: Duration = Duration::from_millis(10);
+/// Floor for the deviation, so a perfectly steady peer is not suspected the
+/// moment one reply is slightly late.
+const MIN_STDDEV: Duration = Duration::from_millis(50);
+pub const PHI_THRESHOLD: f64 = 8.0;
+
+/// What is known about one peer from the RPCs sent to it.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerHealth {
+    pub rtt_mean: Duration,
+    pub rtt_stddev: Duration,
+    pub rto: Duration,
+    pub samples: usize,
+    /// How long the oldest unanswered RPC has been waiting.
+    pub waiting: Duration,
+    pub phi: f64,
+}
+
+#[derive(Default)]
+struct Peer {
+    rtts: VecDeque<Duration>,
+    /// Smoothed round trip and its variation, as in RFC 6298.
+    srtt: Option<(Duration, Duration)>,
+    /// Send time of every unanswered RPC, by message id.
+    outstanding: HashMap<u32, Instant>,
+}
+
+impl Peer {
+    fn rto(&self) -> Duration {
+        match self.srtt {
+            Some((srtt, rttvar)) => (srtt + GRANULARITY.max(4 * rttvar)).clamp(MIN_RTO, MAX_RTO),
+            None => INITIAL_RTT,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        if self.rtts.len() == WINDOW {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+        self.srtt = Some(match self.srtt {
+            None => (rtt, rtt / 2),
+            Some((srtt, rttvar)) => {
+                let err = srtt.abs_diff(rtt);
+                (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + err / 4)
+            }
+        });
+    }
+
+    fn mean(&self) -> Duration {
+        match self.rtts.len() {
+            0 => INITIAL_RTT,
+            n => self.rtts.iter().sum::<Duration>() / n as u32,
+        }
+    }
+
+    fn stddev(&self, mean: Duration) -> Duration {
+        let n = self.rtts.len();
+        if n < 2 {
+            return MIN_STDDEV.max(mean / 4);
+        }
+        let mean = mean.as_secs_f64();
+        let var = self
+            .rtts
+            .iter()
+            .map(|rtt| (rtt.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / (n - 1) as f64;
+        Duration::from_secs_f64(var.sqrt()).max(MIN_STDDEV)
+    }
+}
+
+/// Phi-accrual failure detection fed by RPC round trips. Rather than
+/// heartbeats, the evidence is the oldest RPC a peer has not answered yet:
+/// phi is how unlikely it is, given the round trips measured so far, that a
+/// healthy peer would still not have replied. Phi of 1 means a 10% chance,
+/// 2 a 1% chance and so on.
+#[derive(Default)]
+pub struct Health {
+    peers: DashMap<CompactString, Peer>,
+}
+
+impl Health {
+    pub fn get(&self, peer: &str) -> Option<PeerHealth> {
+        let p = self.peers.get(peer)?;
+        let rtt_mean = p.mean();
+        let rtt_stddev = p.stddev(rtt_mean);
+        let waiting = p
+            .outstanding
+            .values()
+            .min()
+            .map_or(Duration::ZERO, |sent| sent.elapsed());
+        Some(PeerHealth {
+            rtt_mean,
+            rtt_stddev,
+            rto: p.rto(),
+            samples: p.rtts.len(),
+            waiting,
+            phi: phi(waiting, rtt_mean, rtt_stddev),
+        })
+    }
+
+    /// How long to wait for a reply before retransmitting a request.
+    pub fn rto(&self, peer: &str) -> Duration {
+        self.peers.get(peer).map_or(INITIAL_RTT, |p| p.rto())
+    }
+
+    pub fn phi(&self, peer: &str) -> f64 {
+        self.get(peer).map_or(0.0, |h| h.phi)
+    }
+
+    /// Whether the peer has gone quiet for long enough to route around it.
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        self.phi(peer) > PHI_THRESHOLD
+    }
+
+    pub(crate) fn sent(&self, peer: &str, msg_id: u32) {
+        self.peers
+            .entry(peer.into())
+            .or_default()
+            .outstanding
+            .insert(msg_id, Instant::now());
+    }
+
+    /// Forgets an RPC, recording its round trip if it was answered. Following
+    /// Karn's algorithm, replies to retransmitted requests are not sampled
+    /// since it is unknown which copy they answer.
+    pub(crate) fn done(&self, peer: &str, msg_id: u32, answered: bool, retransmitted: bool) {
+        let Some(mut p) = self.peers.get_mut(peer) else {
+            return;
+        };
+        let Some(sent) = p.outstanding.remove(&msg_id) else {
+            return;
+        };
+        if answered && !retransmitted {
+            p.sample(sent.elapsed());
+        }
+    }
+}
+
+/// Phi of a normally distributed round trip, using the logistic
+/// approximation of the normal CDF from Akka's detector.
+fn phi(waiting: Duration, mean: Duration, stddev: Duration) -> f64 {
+    if waiting.is_zero() {
+        return 0.0;
+    }
+    let y = (waiting.as_secs_f64() - mean.as_secs_f64()) / stddev.as_secs_f64();
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if waiting > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Completes one RPC to `n1` that took `rtt`.
+    async fn round_trip(health: &Health, msg_id: u32, rtt: Duration) {
+        health.sent("n1", msg_id);
+        sleep(rtt).await;
+        health.done("n1", msg_id, true, false);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rto_follows_rfc_6298() {
+        let health = Health::default();
+        assert_eq!(health.rto("n1"), INITIAL_RTT);
+
+        // srtt = 100, rttvar = 50.
+        round_trip(&health, 1, 100 * MS).await;
+        assert_eq!(health.rto("n1"), 300 * MS);
+        // srtt = 100, rttvar = 3/4 * 50.
+        round_trip(&health, 2, 100 * MS).await;
+        assert_eq!(health.rto("n1"), 250 * MS);
+        // srtt = 7/8 * 100 + 1/8 * 180 = 110, rttvar = 3/4 * 37.5 + 1/4 * 80.
+        round_trip(&health, 3, 180 * MS).await;
+        assert_eq!(
+            health.rto("n1"),
+            110 * MS + 4 * Duration::from_micros(48_125)
+        );
+
+        let h = health.get("n1").unwrap();
+        assert_eq!(h.samples, 3);
+        assert_eq!(h.rtt_mean, 380 * MS / 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rto_is_clamped() {
+        let health = Health::default();
+        for id in 0..50 {
+            round_trip(&health, id, 100 * MS).await;
+        }
+        // rttvar decays towards zero, leaving the timer granularity.
+        assert_eq!(health.rto("n1"), 100 * MS + GRANULARITY);
+        for id in 50..100 {
+            round_trip(&health, id, MS).await;
+        }
+        assert_eq!(health.rto("n1"), MIN_RTO);
+        for id in 100..110 {
+            round_trip(&health, id, 3 * MAX_RTO).await;
+        }
+        assert_eq!(health.rto("n1"), MAX_RTO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmitted_and_unanswered_rpcs_are_not_sampled() {
+        let health = Health::default();
+        round_trip(&health, 1, 100 * MS).await;
+        for (id, answered, retransmitted) in [(2, true, true), (3, false, false)] {
+            health.sent("n1", id);
+            sleep(900 * MS).await;
+            health.done("n1", id, answered, retransmitted);
+        }
+        let h = health.get("n1").unwrap();
+        assert_eq!((h.samples, h.rtt_mean), (1, 100 * MS));
+        assert_eq!(h.waiting, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn phi_crosses_the_threshold_as_a_reply_gets_overdue() {
+        let health = Health::default();
+        for id in 0..10 {
+            round_trip(&health, id, 100 * MS).await;
+        }
+        assert_eq!(health.phi("n1"), 0.0);
+        assert_eq!(health.phi("n2"), 0.0, "unknown peer");
+
+        // Steady 100ms round trips, with the deviation floored at 50ms.
+        health.sent("n1", 10);
+        let mut last = 0.0;
+        for (waited, suspected) in [(100, false), (200, false), (300, false), (400, true)] {
+            sleep(Duration::from_millis(waited) - health.get("n1").unwrap().waiting).await;
+            let phi = health.phi("n1");
+            assert!(phi > last, "phi fell to {phi} at {waited}ms");
+            assert_eq!(
+                health.is_suspected("n1"),
+                suspected,
+                "phi {phi} at {waited}ms"
+            );
+            last = phi;
+        }
+        assert!(last > PHI_THRESHOLD);
+
+        // The reply clears the suspicion.
+        health.done("n1", 10, true, false);
+        assert!(!health.is_suspected("n1"));
+    }
+}
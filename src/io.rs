@@ -1,21 +1,297 @@
-use std::io::{BufRead, Write};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use compact_str::CompactString;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::sync::mpsc;
-use tracing::error;
+use tracing::{debug, error, info};
 
-use crate::message::Message;
+use crate::{
+    error::{JsonSerError, NodeError, WithReason},
+    message::{Init, InitOk, Message},
+};
 
-pub fn stdin(tx: mpsc::Sender<Message<Value>>) {
+const RECORD_ENV: &str = "VORTEX_RECORD";
+const REPLAY_ENV: &str = "VORTEX_REPLAY";
+const LISTEN_ENV: &str = "VORTEX_LISTEN";
+const REPLAY_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record<P> {
+    /// Microseconds since the Unix epoch.
+    pub ts: u64,
+    pub dir: Direction,
+    pub msg: Message<P>,
+}
+
+#[derive(Serialize)]
+struct RecordRef<'a, P> {
+    ts: u64,
+    dir: Direction,
+    msg: &'a Message<P>,
+}
+
+/// Appends every message crossing the node boundary to a JSONL file.
+pub struct Recorder {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, NodeError> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_reason(format!("Failed to create recording: {}", path.display()))?;
+        info!(path = %path.display(), "Recording messages");
+        Ok(Self {
+            out: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn record<P: Serialize>(&self, dir: Direction, msg: &Message<P>) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let mut out = self.out.lock();
+        let res = serde_json::to_writer(&mut *out, &RecordRef { ts, dir, msg })
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(out))
+            .and_then(|_| out.flush());
+        if let Err(e) = res {
+            error!(?e, "Failed to record message");
+        }
+    }
+}
+
+/// A recorded run, split into what is fed back to the node and what is used
+/// to answer the node's own requests.
+pub struct Replay {
+    init: Record<Value>,
+    inbound: Vec<Record<Value>>,
+    requests: Vec<Message<Value>>,
+    replies: HashMap<(CompactString, u32), Message<Value>>,
+    end: u64,
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, NodeError> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_reason(format!("Failed to open recording: {}", path.display()))?;
+
+        let mut init = None;
+        let mut inbound = vec![];
+        let mut requests = vec![];
+        let mut replies = HashMap::new();
+        let mut sent = HashSet::new();
+        let mut end = 0;
+        for line in BufReader::new(file).lines() {
+            let line = line.with_reason("Failed to read recording")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record<Value> =
+                serde_json::from_str(&line).with_reason("Failed to parse recorded message")?;
+            end = end.max(record.ts);
+            let body = &record.msg.body;
+            match (record.dir, body.in_reply_to) {
+                (Direction::In, _) if init.is_none() => init = Some(record),
+                (Direction::In, None) => inbound.push(record),
+                (Direction::In, Some(id)) => {
+                    replies
+                        .entry((record.msg.src.clone(), id))
+                        .or_insert(record.msg);
+                }
+                (Direction::Out, None) => {
+                    // Retries resend the same message id; keep the first copy only.
+                    if let Some(id) = body.msg_id {
+                        if sent.insert((record.msg.dst.clone(), id)) {
+                            requests.push(record.msg);
+                        }
+                    }
+                }
+                (Direction::Out, Some(_)) => {}
+            }
+        }
+
+        Ok(Self {
+            init: init.with_reason("Recording has no init message")?,
+            inbound,
+            requests,
+            replies,
+            end,
+        })
+    }
+}
+
+pub enum Io {
+    Stdio {
+        record_dir: Option<PathBuf>,
+    },
+    /// Lines exchanged with a single client connected over TCP.
+    Tcp {
+        input: BufReader<TcpStream>,
+        output: TcpStream,
+        record_dir: Option<PathBuf>,
+    },
+    Replay(Replay),
+}
+
+impl Io {
+    /// Plain stdin/stdout unless `VORTEX_REPLAY` names a recording to replay,
+    /// or `VORTEX_LISTEN` an address to accept one connection on and use in
+    /// their place. `VORTEX_RECORD` names a directory receiving one
+    /// `<node_id>.jsonl` per node.
+    pub fn from_env() -> Result<Self, NodeError> {
+        if let Some(path) = std::env::var_os(REPLAY_ENV) {
+            return Ok(Io::Replay(Replay::load(path)?));
+        }
+        let record_dir = std::env::var_os(RECORD_ENV).map(PathBuf::from);
+        match std::env::var(LISTEN_ENV) {
+            Ok(addr) => {
+                let listener =
+                    TcpListener::bind(&addr).with_reason(format!("Failed to listen on {addr}"))?;
+                info!(addr, "Waiting for a client");
+                let (conn, peer) = listener.accept().with_reason("Failed to accept a client")?;
+                info!(%peer, "Client connected");
+                conn.set_nodelay(true)
+                    .with_reason("Failed to configure the client connection")?;
+                Ok(Io::Tcp {
+                    output: conn
+                        .try_clone()
+                        .with_reason("Failed to clone the client connection")?,
+                    input: BufReader::new(conn),
+                    record_dir,
+                })
+            }
+            Err(_) => Ok(Io::Stdio { record_dir }),
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(Message<Init>, Option<Arc<Recorder>>), NodeError> {
+        match self {
+            Io::Stdio { record_dir } => read_init(&mut std::io::stdin().lock(), record_dir),
+            Io::Tcp {
+                input, record_dir, ..
+            } => read_init(input, record_dir),
+            Io::Replay(replay) => {
+                let init_msg = serde_json::from_value(replay.init.msg.ser_val()?)
+                    .with_reason("Failed to parse recorded init message")?;
+                info!("Replaying recorded messages");
+                Ok((init_msg, None))
+            }
+        }
+    }
+
+    pub fn init_ok(
+        &self,
+        reply: &Message<InitOk>,
+        recorder: Option<&Recorder>,
+    ) -> Result<(), NodeError> {
+        let init_ok = reply.ser_str()?;
+        match self {
+            Io::Tcp { output, .. } => writeln!(&*output, "{init_ok}"),
+            Io::Stdio { .. } | Io::Replay(_) => writeln!(std::io::stdout().lock(), "{init_ok}"),
+        }
+        .with_reason("Failed to write init_ok")?;
+        if let Some(recorder) = recorder {
+            recorder.record(Direction::Out, reply);
+        }
+        Ok(())
+    }
+
+    pub fn spawn(
+        self,
+        recorder: Option<Arc<Recorder>>,
+        tx: mpsc::Sender<Message<Value>>,
+        rx: mpsc::Receiver<Message<Value>>,
+    ) {
+        match self {
+            Io::Stdio { .. } => {
+                let r = recorder.clone();
+                std::thread::spawn(|| read_messages(std::io::stdin().lock(), tx, r));
+                std::thread::spawn(|| write_messages(std::io::stdout().lock(), rx, recorder));
+            }
+            Io::Tcp { input, output, .. } => {
+                let r = recorder.clone();
+                std::thread::spawn(|| read_messages(input, tx, r));
+                std::thread::spawn(|| write_messages(BufWriter::new(output), rx, recorder));
+            }
+            Io::Replay(replay) => {
+                let weak = tx.downgrade();
+                let Replay {
+                    init,
+                    inbound,
+                    requests,
+                    replies,
+                    end,
+                } = replay;
+                std::thread::spawn(move || replay_in(tx, init.ts, inbound, end));
+                std::thread::spawn(move || {
+                    replay_out(std::io::stdout().lock(), rx, weak, requests, replies)
+                });
+            }
+        }
+    }
+}
+
+fn read_init(
+    input: &mut impl BufRead,
+    record_dir: &Option<PathBuf>,
+) -> Result<(Message<Init>, Option<Arc<Recorder>>), NodeError> {
+    let mut line = String::new();
+    input
+        .read_line(&mut line)
+        .with_reason("Failed to read init message")?;
+    let init_msg: Message<Init> =
+        serde_json::from_str(&line).with_reason("Failed to parse init message")?;
+    debug!(msg = line, "Received init message");
+
+    let recorder = match record_dir {
+        Some(dir) => {
+            let recorder =
+                Recorder::create(dir.join(format!("{}.jsonl", init_msg.body.payload.node_id)))?;
+            recorder.record(Direction::In, &init_msg);
+            Some(Arc::new(recorder))
+        }
+        None => None,
+    };
+    Ok((init_msg, recorder))
+}
+
+pub fn read_messages(
+    mut input: impl BufRead,
+    tx: mpsc::Sender<Message<Value>>,
+    recorder: Option<Arc<Recorder>>,
+) {
     let mut buffer = String::new();
-    let mut stdin = std::io::stdin().lock();
-    while stdin
+    while input
         .read_line(&mut buffer)
-        .expect("Failed to read from stdin")
+        .expect("Failed to read message")
         != 0
     {
         match serde_json::from_str(&buffer) {
-            Ok(msg) => tx.blocking_send(msg).expect("Failed to send from stdin"),
+            Ok(msg) => {
+                if let Some(recorder) = &recorder {
+                    recorder.record(Direction::In, &msg);
+                }
+                tx.blocking_send(msg).expect("Failed to send from input")
+            }
             Err(e) => {
                 error!(buffer, ?e, "Failed to parse message");
                 return;
@@ -25,10 +301,238 @@ pub fn stdin(tx: mpsc::Sender<Message<Value>>) {
     }
 }
 
-pub fn stdout(mut rx: mpsc::Receiver<Message<Value>>) {
-    let mut output = std::io::stdout().lock();
+pub fn write_messages(
+    mut output: impl Write,
+    mut rx: mpsc::Receiver<Message<Value>>,
+    recorder: Option<Arc<Recorder>>,
+) {
     while let Some(msg) = rx.blocking_recv() {
-        serde_json::to_writer(&mut output, &msg).expect("Failed to serialize to stdout");
-        writeln!(output).expect("Failed to write to stdout");
+        serde_json::to_writer(&mut output, &msg).expect("Failed to serialize message");
+        writeln!(output)
+            .and_then(|_| output.flush())
+            .expect("Failed to write message");
+        if let Some(recorder) = &recorder {
+            recorder.record(Direction::Out, &msg);
+        }
+    }
+}
+
+/// Feeds recorded requests back with their original spacing, then keeps the
+/// inbound channel open long enough for the last replies to be served.
+fn replay_in(
+    tx: mpsc::Sender<Message<Value>>,
+    start_ts: u64,
+    inbound: Vec<Record<Value>>,
+    end: u64,
+) {
+    let start = Instant::now();
+    let offset = |ts: u64| Duration::from_micros(ts.saturating_sub(start_ts));
+    for record in inbound {
+        std::thread::sleep(offset(record.ts).saturating_sub(start.elapsed()));
+        if tx.blocking_send(record.msg).is_err() {
+            return;
+        }
+    }
+    std::thread::sleep((offset(end) + REPLAY_GRACE).saturating_sub(start.elapsed()));
+}
+
+/// Echoes outbound messages to `output` and answers each new request with the
+/// reply recorded for the matching request of the original run.
+fn replay_out(
+    mut output: impl Write,
+    mut rx: mpsc::Receiver<Message<Value>>,
+    tx: mpsc::WeakSender<Message<Value>>,
+    mut requests: Vec<Message<Value>>,
+    mut replies: HashMap<(CompactString, u32), Message<Value>>,
+) {
+    let mut served = HashSet::new();
+    while let Some(msg) = rx.blocking_recv() {
+        serde_json::to_writer(&mut output, &msg).expect("Failed to serialize message");
+        writeln!(output).expect("Failed to write message");
+
+        let (None, Some(id)) = (msg.body.in_reply_to, msg.body.msg_id) else {
+            continue;
+        };
+        if !served.insert((msg.dst.clone(), id)) {
+            continue;
+        }
+
+        let reply = take_matching(&mut requests, &msg)
+            .and_then(|recorded| replies.remove(&(msg.dst.clone(), recorded)));
+        match (reply, tx.upgrade()) {
+            (Some(mut reply), Some(tx)) => {
+                reply.dst = msg.src.clone();
+                reply.body.in_reply_to = Some(id);
+                if tx.blocking_send(reply).is_err() {
+                    return;
+                }
+            }
+            (Some(_), None) => return,
+            (None, _) => debug!(dst = msg.dst.as_str(), id, "No recorded reply"),
+        }
+    }
+}
+
+/// Prefers a recorded request with an identical payload, falling back to the
+/// first one of the same type for payloads that are not deterministic.
+fn take_matching(requests: &mut Vec<Message<Value>>, msg: &Message<Value>) -> Option<u32> {
+    let same_dst = |r: &&Message<Value>| r.dst == msg.dst;
+    let pos = requests
+        .iter()
+        .position(|r| same_dst(&r) && r.body.payload == msg.body.payload)
+        .or_else(|| {
+            requests.iter().position(|r| {
+                same_dst(&r) && r.body.payload.get("type") == msg.body.payload.get("type")
+            })
+        })?;
+    requests.remove(pos).body.msg_id
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, io::Read, sync::mpsc as std_mpsc};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{node::Node, serve, service::KvService};
+
+    /// Input lines handed over one at a time, ending once the sender is gone.
+    struct LineSource {
+        rx: std_mpsc::Receiver<String>,
+        buf: VecDeque<u8>,
+    }
+
+    impl Read for LineSource {
+        fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+            if self.buf.is_empty() {
+                let Ok(line) = self.rx.recv() else {
+                    return Ok(0);
+                };
+                self.buf.extend(line.bytes().chain([b'\n']));
+            }
+            let n = out.len().min(self.buf.len());
+            for (o, b) in out.iter_mut().zip(self.buf.drain(..n)) {
+                *o = b;
+            }
+            Ok(n)
+        }
+    }
+
+    /// Output split into lines, closed once the writer is dropped.
+    struct LineSink {
+        tx: mpsc::UnboundedSender<String>,
+        buf: Vec<u8>,
+    }
+
+    impl Write for LineSink {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            for &b in bytes {
+                if b == b'\n' {
+                    let line = String::from_utf8(std::mem::take(&mut self.buf)).unwrap();
+                    _ = self.tx.send(line);
+                } else {
+                    self.buf.push(b);
+                }
+            }
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sink() -> (LineSink, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (LineSink { tx, buf: vec![] }, rx)
+    }
+
+    fn line(src: &str, msg_id: u32, in_reply_to: Option<u32>, mut payload: Value) -> String {
+        payload["msg_id"] = msg_id.into();
+        if let Some(id) = in_reply_to {
+            payload["in_reply_to"] = id.into();
+        }
+        json!({"src": src, "dest": "n0", "body": payload}).to_string()
+    }
+
+    /// Answers a client's read with the value of a lin-kv key.
+    fn start(init: Init, rx: mpsc::Receiver<Message<Value>>, tx: mpsc::Sender<Message<Value>>) {
+        let node = Arc::new(Node::from_init(init, tx));
+        tokio::spawn(serve(node, rx, |msg, node| async move {
+            if KvService::from_name(&msg.src).is_some() {
+                return node.handle_kv(&msg);
+            }
+            let value = node.kv_read(KvService::Lin, "x").await?;
+            node.reply(&msg, json!({"type": "read_ok", "value": value}))
+                .await
+        }));
+    }
+
+    #[tokio::test]
+    async fn replay_reproduces_a_recorded_session() {
+        let dir = std::env::temp_dir().join(format!("vortex-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (lines, rx) = std_mpsc::channel();
+        let mut input = BufReader::new(LineSource {
+            rx,
+            buf: VecDeque::new(),
+        });
+        let init = json!({"type": "init", "node_id": "n0", "node_ids": ["n0"]});
+        lines.send(line("c0", 0, None, init)).unwrap();
+        let (init, recorder) = read_init(&mut input, &Some(dir.clone())).unwrap();
+        let (in_tx, in_rx) = mpsc::channel(8);
+        let (out_tx, out_rx) = mpsc::channel(8);
+        start(init.body.payload, in_rx, out_tx);
+        let (output, mut recorded) = sink();
+        let r = recorder.clone();
+        std::thread::spawn(move || read_messages(input, in_tx, r));
+        std::thread::spawn(move || write_messages(output, out_rx, recorder));
+
+        let mut outputs = vec![];
+        for (client_id, value) in [(1, 5), (2, 7)] {
+            lines
+                .send(line("c1", client_id, None, json!({"type": "read"})))
+                .unwrap();
+            let request = recorded.recv().await.unwrap();
+            let kv_id = serde_json::from_str::<Message<Value>>(&request)
+                .unwrap()
+                .body
+                .msg_id;
+            let read_ok = json!({"type": "read_ok", "value": value});
+            lines
+                .send(line("lin-kv", 10 + client_id, kv_id, read_ok))
+                .unwrap();
+            let reply = recorded.recv().await.unwrap();
+            assert!(reply.contains(&format!(r#""value":{value}"#)), "{reply}");
+            outputs.extend([request, reply]);
+        }
+        drop(lines);
+        assert_eq!(recorded.recv().await, None);
+
+        let replay = Replay::load(dir.join("n0.jsonl")).unwrap();
+        let init = serde_json::from_value(replay.init.msg.body.payload.clone()).unwrap();
+        let (in_tx, in_rx) = mpsc::channel(8);
+        let (out_tx, out_rx) = mpsc::channel(8);
+        start(init, in_rx, out_tx);
+        let (output, mut replayed) = sink();
+        let weak = in_tx.downgrade();
+        let Replay {
+            init,
+            inbound,
+            requests,
+            replies,
+            end,
+        } = replay;
+        std::thread::spawn(move || replay_in(in_tx, init.ts, inbound, end));
+        std::thread::spawn(move || replay_out(output, out_rx, weak, requests, replies));
+
+        let mut replayed_outputs = vec![];
+        while let Some(line) = replayed.recv().await {
+            replayed_outputs.push(line);
+        }
+        assert_eq!(replayed_outputs, outputs);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -1,8 +1,4 @@
-use std::{
-    pin::Pin,
-    sync::Arc,
-    task::{Context, Poll},
-};
+use std::sync::Arc;
 
 use error::NodeError;
 use futures::Future;
@@ -11,14 +7,25 @@ use miette::IntoDiagnostic;
 
 use node::Node;
 use serde_json::Value;
+use tokio::sync::mpsc;
 use tracing::info;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+pub mod checker;
+pub mod client;
+pub mod crdt;
 pub mod error;
+pub mod health;
 pub mod io;
+pub mod membership;
 pub mod message;
 pub mod node;
+pub mod plumtree;
+pub mod ranges;
 pub mod service;
+pub mod sim;
+pub mod topology;
+pub mod txn;
 
 pub fn init_tracing() -> miette::Result<()> {
     let otel = if std::env::var_os("OTEL_SERVICE_NAME").is_some() {
@@ -43,68 +50,71 @@ pub fn init_tracing() -> miette::Result<()> {
     Ok(())
 }
 
-pub struct Main<Fut>
+/// Runs a node speaking the Maelstrom protocol on stdin/stdout, or an
+/// in-process cluster of them when invoked as `<bin> sim [options]`.
+///
+/// `setup` is called once per node to create its state and background tasks,
+/// and returns the handler for that node's messages.
+pub async fn main_loop<S, F, FutF>(setup: S) -> miette::Result<()>
 where
-    Fut: Future<Output = miette::Result<()>>,
+    S: Fn(Arc<Node>) -> F + Sync,
+    F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
+    FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
 {
-    pub node: Arc<Node>,
-    pub fut: Fut,
-}
+    if std::env::args().nth(1).as_deref() == Some("sim") {
+        return sim::run(setup).await;
+    }
 
-impl<Fut> Future for Main<Fut>
-where
-    Fut: Future<Output = miette::Result<()>>,
-{
-    type Output = miette::Result<()>;
+    info!("Starting node...");
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        unsafe { self.map_unchecked_mut(|s| &mut s.fut) }.poll(cx)
-    }
+    let (node, rx) = {
+        let (node, rx) = Node::new()?;
+        (Arc::new(node), rx)
+    };
+    let func = setup(node.clone());
+    let res = serve(node, rx, func).await;
+
+    opentelemetry::global::shutdown_tracer_provider();
+    Ok(res?)
 }
 
-pub fn main_loop<F, FutF>(
+pub async fn serve<F, FutF>(
+    node: Arc<Node>,
+    mut rx: mpsc::Receiver<Message<Value>>,
     func: F,
-) -> Result<Main<impl Future<Output = miette::Result<()>>>, NodeError>
+) -> Result<(), NodeError>
 where
     F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
     FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
 {
-    info!("Starting node...");
-
-    let (node, mut rx) = {
-        let (node, rx) = Node::new()?;
-        (Arc::new(node), rx)
-    };
-    let n = node.clone();
+    let (c_tx, mut c_rx) = mpsc::channel(1);
+    let mut expiry = tokio::time::interval(node::DEDUPE_WINDOW);
 
-    let (c_tx, mut c_rx) = tokio::sync::mpsc::channel(1);
+    loop {
+        tokio::select! {
+            biased;
+            err = c_rx.recv() => if let Some(err) = err {
+                break Err(err);
+            },
+            _ = expiry.tick() => node.expire_handled(),
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    let node = node.clone();
+                    let c_tx = c_tx.clone();
+                    let func = func.clone();
 
-    let fut = async move {
-        let res = loop {
-            tokio::select! {
-                msg = rx.recv() => match msg {
-                    Some(msg) => {
-                        let node = node.clone();
-                        let c_tx = c_tx.clone();
-                        let func = func.clone();
-
-                        tokio::spawn(async move {
-                            if let Err(e) = func(msg, node).await {
-                                _ =  c_tx.send(e).await;
-                            }
-                        });
-                    },
-                    None => break Ok(())
+                    tokio::spawn(async move {
+                        let res = match node.is_duplicate(&msg).await {
+                            Ok(false) => func(msg, node).await,
+                            res => res.map(|_| ()),
+                        };
+                        if let Err(e) = res {
+                            _ =  c_tx.send(e).await;
+                        }
+                    });
                 },
-                err = c_rx.recv() => if let Some(err) = err {
-                    break Err(err);
-                }
-            }
-        };
-
-        opentelemetry::global::shutdown_tracer_provider();
-        Ok(res?)
-    };
-
-    Ok(Main { node: n, fut })
+                None => break Ok(())
+            },
+        }
+    }
 }
@@ -0,0 +1,585 @@
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+
+use compact_str::CompactString;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{sync::broadcast, time::Instant};
+use tracing::{debug, info, instrument};
+
+use crate::{
+    error::{JsonDeError, NodeError},
+    message::Message,
+    node::Node,
+};
+
+const MEMBERSHIP_ENV: &str = "VORTEX_MEMBERSHIP";
+const PROTOCOL_PERIOD: Duration = Duration::from_millis(1000);
+const PING_TIMEOUT: Duration = Duration::from_millis(300);
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Peers asked to probe a target that did not answer a direct ping.
+const PING_REQ_FANOUT: usize = 3;
+const MAX_PIGGYBACK: usize = 8;
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// A membership rumour. Higher incarnations override lower ones, and at the
+/// same incarnation suspicion overrides liveness. A node declared dead only
+/// comes back by refuting its death with a higher incarnation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    pub node: CompactString,
+    pub status: Status,
+    pub incarnation: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Swim {
+    SwimPing {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+    SwimPingReq {
+        seq: u64,
+        target: CompactString,
+        updates: Vec<Update>,
+    },
+    SwimAck {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+}
+
+impl Swim {
+    /// Whether `msg` belongs to the protocol and should go to [`Membership::handle`].
+    pub fn matches(msg: &Message<Value>) -> bool {
+        msg.body
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .is_some_and(|t| t.starts_with("swim_"))
+    }
+}
+
+/// A change in some peer's status, as seen by this node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Alive(CompactString),
+    Suspect(CompactString),
+    Dead(CompactString),
+}
+
+struct Member {
+    status: Status,
+    incarnation: u32,
+    since: Instant,
+}
+
+/// A probe sent on behalf of another node, to forward the ack to.
+struct Relay {
+    requester: CompactString,
+    seq: u64,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    incarnation: u32,
+    members: HashMap<CompactString, Member>,
+    /// Rumours still to be piggybacked, with how many more times to send each.
+    rumours: Vec<(Update, usize)>,
+    /// Our own outstanding probe, and whether it has been acknowledged.
+    probe: Option<(u64, bool)>,
+    relays: HashMap<u64, Relay>,
+    next_seq: u64,
+    /// Round-robin probe order, reshuffled after every pass.
+    order: Vec<CompactString>,
+}
+
+/// SWIM-style membership: every protocol period each node pings one peer,
+/// falling back to indirect pings through `PING_REQ_FANOUT` others. A peer
+/// that answers neither is suspected, and declared dead if it does not refute
+/// the suspicion within `SUSPECT_TIMEOUT`. Status changes spread by riding
+/// along on the protocol's own messages.
+pub struct Membership {
+    node: Arc<Node>,
+    state: Mutex<State>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Membership {
+    /// Whether `VORTEX_MEMBERSHIP=swim` asks for a failure detector. It costs a
+    /// ping and an ack per node every protocol period, so it is off by default.
+    pub fn enabled() -> Result<bool, NodeError> {
+        match std::env::var(MEMBERSHIP_ENV).as_deref() {
+            Err(_) | Ok("off") => Ok(false),
+            Ok("swim") => Ok(true),
+            Ok(other) => Err(NodeError::new(format!("Unknown membership: {other}"))),
+        }
+    }
+
+    pub fn new(node: Arc<Node>) -> Arc<Self> {
+        let now = Instant::now();
+        let members = node
+            .node_ids
+            .iter()
+            .filter(|n| **n != node.id)
+            .map(|n| {
+                let member = Member {
+                    status: Status::Alive,
+                    incarnation: 0,
+                    since: now,
+                };
+                (n.clone(), member)
+            })
+            .collect();
+        Arc::new(Self {
+            node,
+            state: Mutex::new(State {
+                members,
+                ..State::default()
+            }),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        })
+    }
+
+    /// Peers currently believed to be up, including suspected ones.
+    pub fn live(&self) -> Vec<CompactString> {
+        let mut live = self
+            .state
+            .lock()
+            .members
+            .iter()
+            .filter(|(_, m)| m.status != Status::Dead)
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        live.sort();
+        live
+    }
+
+    pub fn status(&self, peer: &str) -> Option<Status> {
+        self.state.lock().members.get(peer).map(|m| m.status)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    #[instrument("Membership", skip_all, fields(src = msg.src.as_str()))]
+    pub async fn handle(&self, msg: &Message<Value>) -> Result<(), NodeError> {
+        let src = &msg.src;
+        match Swim::de(&msg.body.payload)? {
+            Swim::SwimPing { seq, updates } => {
+                self.apply(src, updates);
+                let updates = self.piggyback();
+                self.node
+                    .send(src.clone(), Swim::SwimAck { seq, updates })
+                    .await
+            }
+            Swim::SwimPingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.apply(src, updates);
+                let (relay, updates) = {
+                    let mut state = self.state.lock();
+                    let relay = state.next_seq();
+                    let entry = Relay {
+                        requester: src.clone(),
+                        seq,
+                        since: Instant::now(),
+                    };
+                    state.relays.insert(relay, entry);
+                    (relay, take_rumours(&mut state))
+                };
+                let ping = Swim::SwimPing {
+                    seq: relay,
+                    updates,
+                };
+                self.node.send(target, ping).await
+            }
+            Swim::SwimAck { seq, updates } => {
+                self.apply(src, updates);
+                let relay = {
+                    let mut state = self.state.lock();
+                    match state.relays.remove(&seq) {
+                        Some(relay) => Some(relay),
+                        None => {
+                            if let Some((probe, acked)) = &mut state.probe {
+                                *acked |= *probe == seq;
+                            }
+                            None
+                        }
+                    }
+                };
+                match relay {
+                    Some(Relay { requester, seq, .. }) => {
+                        let updates = self.piggyback();
+                        let ack = Swim::SwimAck { seq, updates };
+                        self.node.send(requester, ack).await
+                    }
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Probes one peer per protocol period and expires suspicions and relays.
+    /// Runs until the node stops.
+    pub async fn run(self: Arc<Self>) -> Result<(), NodeError> {
+        loop {
+            let start = Instant::now();
+            self.expire();
+            if let Some(target) = self.next_target() {
+                self.probe(target).await?;
+            }
+            tokio::time::sleep_until(start + PROTOCOL_PERIOD).await;
+        }
+    }
+
+    async fn probe(&self, target: CompactString) -> Result<(), NodeError> {
+        let seq = {
+            let mut state = self.state.lock();
+            let seq = state.next_seq();
+            state.probe = Some((seq, false));
+            seq
+        };
+        let updates = self.piggyback();
+        self.node
+            .send(target.clone(), Swim::SwimPing { seq, updates })
+            .await?;
+        tokio::time::sleep(PING_TIMEOUT).await;
+        if self.state.lock().probe_acked() {
+            return Ok(());
+        }
+
+        let helpers = {
+            let state = self.state.lock();
+            let mut helpers = state
+                .members
+                .iter()
+                .filter(|(n, m)| **n != target && m.status == Status::Alive)
+                .map(|(n, _)| n.clone())
+                .collect::<Vec<_>>();
+            helpers.sort_unstable();
+            self.node.rng(|rng| helpers.shuffle(rng));
+            helpers.truncate(PING_REQ_FANOUT);
+            helpers
+        };
+        debug!(target = target.as_str(), ?helpers, "Indirect probe");
+        for helper in helpers {
+            let updates = self.piggyback();
+            let req = Swim::SwimPingReq {
+                seq,
+                target: target.clone(),
+                updates,
+            };
+            self.node.send(helper, req).await?;
+        }
+        tokio::time::sleep(PROTOCOL_PERIOD - 2 * PING_TIMEOUT).await;
+
+        let mut state = self.state.lock();
+        let acked = state.probe_acked();
+        state.probe = None;
+        if !acked {
+            let incarnation = state.members.get(&target).map_or(0, |m| m.incarnation);
+            let update = Update {
+                node: target,
+                status: Status::Suspect,
+                incarnation,
+            };
+            self.merge(&mut state, update);
+        }
+        Ok(())
+    }
+
+    /// Dead peers stay in the rotation so that either side of a healed
+    /// partition can tell the other it was declared dead.
+    fn next_target(&self) -> Option<CompactString> {
+        let mut state = self.state.lock();
+        if state.order.is_empty() {
+            let mut order = state.members.keys().cloned().collect::<Vec<_>>();
+            order.sort_unstable();
+            self.node.rng(|rng| order.shuffle(rng));
+            state.order = order;
+        }
+        state.order.pop()
+    }
+
+    /// A requester stops waiting for a relayed ack within one protocol period,
+    /// so a relay whose target never answered is dropped after that.
+    fn expire(&self) {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        state.relays.retain(|_, r| now - r.since < PROTOCOL_PERIOD);
+        let expired = state
+            .members
+            .iter()
+            .filter(|(_, m)| m.status == Status::Suspect && now - m.since >= SUSPECT_TIMEOUT)
+            .map(|(n, m)| Update {
+                node: n.clone(),
+                status: Status::Dead,
+                incarnation: m.incarnation,
+            })
+            .collect::<Vec<_>>();
+        for update in expired {
+            self.merge(&mut state, update);
+        }
+    }
+
+    fn apply(&self, src: &CompactString, updates: Vec<Update>) {
+        debug!(
+            src = src.as_str(),
+            count = updates.len(),
+            "Applying rumours"
+        );
+        let mut state = self.state.lock();
+        // Whoever we think is dead cannot refute it unless told about it.
+        if let Some(m) = state.members.get(src).filter(|m| m.status == Status::Dead) {
+            let update = Update {
+                node: src.clone(),
+                status: Status::Dead,
+                incarnation: m.incarnation,
+            };
+            if !state.rumours.iter().any(|(u, _)| *u == update) {
+                spread(&mut state, update);
+            }
+        }
+        for update in updates {
+            self.merge(&mut state, update);
+        }
+    }
+
+    fn merge(&self, state: &mut State, update: Update) {
+        if update.node == self.node.id {
+            // Refute any rumour of our own demise with a newer incarnation.
+            if update.status != Status::Alive && update.incarnation >= state.incarnation {
+                state.incarnation = update.incarnation + 1;
+                let refute = Update {
+                    node: self.node.id.clone(),
+                    status: Status::Alive,
+                    incarnation: state.incarnation,
+                };
+                info!(incarnation = state.incarnation, "Refuting suspicion");
+                spread(state, refute);
+            }
+            return;
+        }
+
+        let Some(member) = state.members.get_mut(&update.node) else {
+            return;
+        };
+        let newer = match (member.status, update.status) {
+            (Status::Dead, Status::Alive) => update.incarnation > member.incarnation,
+            (Status::Dead, _) => false,
+            (_, Status::Dead) => update.incarnation >= member.incarnation,
+            (Status::Alive, Status::Suspect) => update.incarnation >= member.incarnation,
+            _ => update.incarnation > member.incarnation,
+        };
+        if !newer {
+            return;
+        }
+
+        member.status = update.status;
+        member.incarnation = update.incarnation;
+        member.since = Instant::now();
+        let event = match update.status {
+            Status::Alive => Event::Alive(update.node.clone()),
+            Status::Suspect => Event::Suspect(update.node.clone()),
+            Status::Dead => Event::Dead(update.node.clone()),
+        };
+        debug!(?event, "Membership changed");
+        _ = self.events.send(event);
+        spread(state, update);
+    }
+
+    fn piggyback(&self) -> Vec<Update> {
+        take_rumours(&mut self.state.lock())
+    }
+}
+
+impl State {
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    fn probe_acked(&self) -> bool {
+        matches!(self.probe, Some((_, true)))
+    }
+}
+
+/// Queues a rumour for dissemination, replacing older news about the same node.
+fn spread(state: &mut State, update: Update) {
+    // Enough repeats to reach every node with high probability.
+    let repeats = 3 * (state.members.len() + 1).ilog2() as usize + 1;
+    state.rumours.retain(|(u, _)| u.node != update.node);
+    state.rumours.push((update, repeats));
+}
+
+/// Picks the least spread rumours to piggyback on the next message.
+fn take_rumours(state: &mut State) -> Vec<Update> {
+    state
+        .rumours
+        .sort_by_key(|(_, left)| std::cmp::Reverse(*left));
+    let updates = state
+        .rumours
+        .iter_mut()
+        .take(MAX_PIGGYBACK)
+        .map(|(u, left)| {
+            *left -= 1;
+            u.clone()
+        })
+        .collect();
+    state.rumours.retain(|(_, left)| *left > 0);
+    updates
+}
+
+impl fmt::Debug for Membership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.live()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        error::JsonSerError,
+        message::{Body, Init},
+    };
+
+    fn membership() -> (Arc<Membership>, mpsc::Receiver<Message<Value>>) {
+        let (tx, rx) = mpsc::channel(8);
+        let init = Init {
+            node_id: "n0".into(),
+            node_ids: vec!["n0".into(), "n1".into(), "n2".into()],
+        };
+        (Membership::new(Arc::new(Node::from_init(init, tx))), rx)
+    }
+
+    fn update(node: &str, status: Status, incarnation: u32) -> Update {
+        Update {
+            node: node.into(),
+            status,
+            incarnation,
+        }
+    }
+
+    fn msg(src: &str, swim: Swim) -> Message<Value> {
+        Message {
+            src: src.into(),
+            dst: "n0".into(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                payload: swim.ser_val().unwrap(),
+            },
+        }
+    }
+
+    async fn sent(rx: &mut mpsc::Receiver<Message<Value>>) -> (CompactString, Swim) {
+        let msg = rx.recv().await.unwrap();
+        (msg.dst, Swim::de(&msg.body.payload).unwrap())
+    }
+
+    #[tokio::test]
+    async fn newer_rumours_override_older_ones() {
+        let (m, _rx) = membership();
+        let mut events = m.subscribe();
+        let steps = [
+            (Status::Suspect, 0, Status::Suspect),
+            (Status::Alive, 0, Status::Suspect),
+            (Status::Alive, 1, Status::Alive),
+            (Status::Dead, 1, Status::Dead),
+            (Status::Suspect, 2, Status::Dead),
+            (Status::Alive, 1, Status::Dead),
+            (Status::Alive, 2, Status::Alive),
+        ];
+        for (status, incarnation, expected) in steps {
+            m.apply(&"n2".into(), vec![update("n1", status, incarnation)]);
+            assert_eq!(
+                m.status("n1"),
+                Some(expected),
+                "{status:?} at {incarnation}"
+            );
+        }
+        let mut seen = vec![];
+        while let Ok(event) = events.try_recv() {
+            seen.push(event);
+        }
+        assert_eq!(
+            seen,
+            [
+                Event::Suspect("n1".into()),
+                Event::Alive("n1".into()),
+                Event::Dead("n1".into()),
+                Event::Alive("n1".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn refutes_suspicion_of_itself() {
+        let (m, _rx) = membership();
+        m.apply(&"n1".into(), vec![update("n0", Status::Suspect, 3)]);
+        assert!(m.piggyback().contains(&update("n0", Status::Alive, 4)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unrefuted_suspects_are_declared_dead() {
+        let (m, _rx) = membership();
+        m.apply(&"n2".into(), vec![update("n1", Status::Suspect, 0)]);
+        tokio::time::advance(SUSPECT_TIMEOUT - Duration::from_millis(1)).await;
+        m.expire();
+        assert_eq!(m.live(), ["n1", "n2"]);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        m.expire();
+        assert_eq!(m.status("n1"), Some(Status::Dead));
+        assert_eq!(m.live(), ["n2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn relays_forward_acks_and_expire() {
+        let (m, mut rx) = membership();
+        let ping_req = |seq| Swim::SwimPingReq {
+            seq,
+            target: "n2".into(),
+            updates: vec![],
+        };
+
+        m.handle(&msg("n1", ping_req(7))).await.unwrap();
+        let (dst, Swim::SwimPing { seq, .. }) = sent(&mut rx).await else {
+            panic!("expected a ping");
+        };
+        assert_eq!(dst, "n2");
+        let ack = Swim::SwimAck {
+            seq,
+            updates: vec![],
+        };
+        m.handle(&msg("n2", ack)).await.unwrap();
+        let (dst, Swim::SwimAck { seq, .. }) = sent(&mut rx).await else {
+            panic!("expected an ack");
+        };
+        assert_eq!((dst.as_str(), seq), ("n1", 7));
+        assert!(m.state.lock().relays.is_empty());
+
+        m.handle(&msg("n1", ping_req(8))).await.unwrap();
+        assert_eq!(m.state.lock().relays.len(), 1);
+        tokio::time::advance(PROTOCOL_PERIOD).await;
+        m.expire();
+        assert!(m.state.lock().relays.is_empty());
+    }
+}
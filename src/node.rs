@@ -1,44 +1,74 @@
 use core::fmt;
 use std::{
-    io::Write,
     sync::atomic::{AtomicU32, Ordering},
     time::Duration,
 };
 
 use compact_str::{format_compact, CompactString};
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, SeedableRng};
 use serde_json::Value;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 use tracing::{debug, error, info, instrument, Span};
 
 use crate::{
     error::{JsonSerError, NodeError, RpcError, WithReason},
-    io::{stdin, stdout},
+    health::Health,
+    io::Io,
     message::{Body, Init, InitOk, Message, Payload},
 };
 
-const RPC_LATENCY: Duration = Duration::from_millis(300);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+pub const DEDUPE_WINDOW: Duration = Duration::from_secs(30);
 
 pub struct Node {
     pub id: CompactString,
     pub node_ids: Vec<CompactString>,
     pub msg_id: AtomicU32,
     pub out_chan: mpsc::Sender<Message<Value>>,
+    pub health: Health,
+    /// Source of every random choice the node makes, so that a simulation
+    /// replays exactly from its seed.
+    rng: Mutex<StdRng>,
     pending_reply: DashMap<CompactString, oneshot::Sender<Result<Value, RpcError>>>,
+    handled: DashMap<(CompactString, u32), Handled>,
+}
+
+/// A request already handed to the handler, and the reply it produced if any.
+struct Handled {
+    at: Instant,
+    reply: Option<Message<Value>>,
+}
+
+/// Cleans up after an RPC however it ends, so a caller may drop an RPC
+/// future, e.g. on a timeout, without a late reply finding a closed channel.
+struct PendingRpc<'a> {
+    node: &'a Node,
+    peer: &'a str,
+    token: CompactString,
+    msg_id: u32,
+    answered: bool,
+    retransmitted: bool,
+}
+
+impl Drop for PendingRpc<'_> {
+    fn drop(&mut self) {
+        self.node.pending_reply.remove(&self.token);
+        self.node
+            .health
+            .done(self.peer, self.msg_id, self.answered, self.retransmitted);
+    }
 }
 
 impl Node {
     #[instrument("Init", fields(id))]
     pub fn new() -> Result<(Self, mpsc::Receiver<Message<Value>>), NodeError> {
-        let mut line = String::new();
-        std::io::stdin()
-            .read_line(&mut line)
-            .with_reason("Failed to read init message")?;
-        let mut output = std::io::stdout().lock();
-
-        let init_msg: Message<Init> =
-            serde_json::from_str(&line).with_reason("Failed to parse init message")?;
-        debug!(msg = line, "Received init message");
+        let mut io = Io::from_env()?;
+        let (init_msg, recorder) = io.init()?;
         Span::current().record("id", init_msg.body.payload.node_id.as_str());
         let reply = Message {
             src: init_msg.dst,
@@ -49,27 +79,41 @@ impl Node {
                 payload: InitOk {},
             },
         };
-        let init_ok = reply.ser_str()?;
-        writeln!(&mut output, "{init_ok}").with_reason("Failed to write init_ok to stdout")?;
+        io.init_ok(&reply, recorder.as_deref())?;
 
         let (tx_in, rx_in) = mpsc::channel(8);
         let (tx_out, rx_out) = mpsc::channel(8);
 
-        std::thread::spawn(|| stdin(tx_in));
-        std::thread::spawn(|| stdout(rx_out));
+        io.spawn(recorder, tx_in, rx_out);
 
         info!("Node initialized");
 
-        Ok((
-            Self {
-                id: init_msg.body.payload.node_id,
-                node_ids: init_msg.body.payload.node_ids,
-                msg_id: 1.into(),
-                out_chan: tx_out,
-                pending_reply: DashMap::new(),
-            },
-            rx_in,
-        ))
+        Ok((Self::from_init(init_msg.body.payload, tx_out), rx_in))
+    }
+
+    pub fn from_init(init: Init, out_chan: mpsc::Sender<Message<Value>>) -> Self {
+        Self {
+            id: init.node_id,
+            node_ids: init.node_ids,
+            msg_id: 1.into(),
+            out_chan,
+            health: Health::default(),
+            rng: Mutex::new(StdRng::from_entropy()),
+            pending_reply: DashMap::new(),
+            handled: DashMap::new(),
+        }
+    }
+
+    /// Makes the node's random choices follow `seed` rather than entropy.
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            ..self
+        }
+    }
+
+    pub fn rng<R>(&self, f: impl FnOnce(&mut StdRng) -> R) -> R {
+        f(&mut self.rng.lock())
     }
 
     pub async fn send(&self, peer: CompactString, msg: impl Payload) -> Result<(), NodeError> {
@@ -90,25 +134,67 @@ impl Node {
     }
 
     pub async fn reply<T>(&self, from: &Message<T>, msg: impl Payload) -> Result<(), NodeError> {
-        self.out_chan
-            .send(Message {
-                src: from.dst.clone(),
-                dst: from.src.clone(),
-                body: Body {
-                    msg_id: Some(self.msg_id.fetch_add(1, Ordering::AcqRel)),
-                    in_reply_to: from.body.msg_id,
-                    payload: msg.ser_val()?,
-                },
-            })
-            .await
-            .with_reason(format!(
-                "Failed to reply to message: {:?}",
-                from.body.msg_id
-            ))?;
+        let reply = Message {
+            src: from.dst.clone(),
+            dst: from.src.clone(),
+            body: Body {
+                msg_id: Some(self.msg_id.fetch_add(1, Ordering::AcqRel)),
+                in_reply_to: from.body.msg_id,
+                payload: msg.ser_val()?,
+            },
+        };
+        if let (Some(id), None) = (from.body.msg_id, from.body.in_reply_to) {
+            if let Some(mut handled) = self.handled.get_mut(&(from.src.clone(), id)) {
+                handled.reply = Some(reply.clone());
+            }
+        }
+        self.out_chan.send(reply).await.with_reason(format!(
+            "Failed to reply to message: {:?}",
+            from.body.msg_id
+        ))?;
 
         Ok(())
     }
 
+    /// Whether `msg` repeats a request seen within `DEDUPE_WINDOW`, as RPC
+    /// retries do. The reply to the original is sent again if there is one;
+    /// otherwise the original is still being handled and will answer itself.
+    pub async fn is_duplicate(&self, msg: &Message<Value>) -> Result<bool, NodeError> {
+        let (Some(id), None) = (msg.body.msg_id, msg.body.in_reply_to) else {
+            return Ok(false);
+        };
+        let fresh = Handled {
+            at: Instant::now(),
+            reply: None,
+        };
+        let reply = match self.handled.entry((msg.src.clone(), id)) {
+            // The periodic sweep may not have caught up with an expired entry.
+            Entry::Occupied(mut e) if e.get().at.elapsed() >= DEDUPE_WINDOW => {
+                e.insert(fresh);
+                return Ok(false);
+            }
+            Entry::Occupied(e) => e.get().reply.clone(),
+            Entry::Vacant(e) => {
+                e.insert(fresh);
+                return Ok(false);
+            }
+        };
+        debug!(src = msg.src.as_str(), id, "Duplicate request");
+        if let Some(reply) = reply {
+            self.out_chan
+                .send(reply)
+                .await
+                .with_reason("Failed to resend cached reply")?;
+        }
+        Ok(true)
+    }
+
+    /// Frees the requests seen more than `DEDUPE_WINDOW` ago, which
+    /// [`Node::is_duplicate`] already treats as new.
+    pub fn expire_handled(&self) {
+        self.handled.retain(|_, h| h.at.elapsed() < DEDUPE_WINDOW);
+    }
+
     pub async fn rpc<P>(
         &self,
         peer: CompactString,
@@ -127,26 +213,35 @@ impl Node {
                 payload: msg.ser_val()?,
             },
         };
+        let token = format_compact!("{peer}:{msg_id}");
+        let (tx, mut rx) = oneshot::channel();
+        self.pending_reply.insert(token.clone(), tx);
+        self.health.sent(&peer, msg_id);
+        let mut pending = PendingRpc {
+            node: self,
+            peer: &peer,
+            token,
+            msg_id,
+            answered: false,
+            retransmitted: false,
+        };
+
         self.out_chan
             .send(msg.clone())
             .await
             .with_reason("Failed to send initial RPC message")?;
 
-        let token = format_compact!("{peer}:{msg_id}");
-        let (tx, mut rx) = oneshot::channel();
-        self.pending_reply.insert(token.clone(), tx);
-
+        // Back off exponentially while the peer does not answer.
+        let mut rto = self.health.rto(&peer);
         loop {
+            // A reply arriving just as the timeout fires wins, and a fixed
+            // order keeps simulations replayable from their seed.
             tokio::select!(
-                _ = tokio::time::sleep(RPC_LATENCY) => {
-                    self.out_chan
-                        .send(msg.clone())
-                        .await
-                        .with_reason("Failed to send retry RPC message")?;
-                }
+                biased;
                 res = &mut rx => {
                     match res {
                         Ok(res) => {
+                            pending.answered = true;
                             return Ok(res);
                         },
                         Err(_) => {
@@ -155,6 +250,14 @@ impl Node {
                         },
                     }
                 }
+                _ = tokio::time::sleep(rto) => {
+                    pending.retransmitted = true;
+                    rto = (rto * 2).min(MAX_BACKOFF);
+                    self.out_chan
+                        .send(msg.clone())
+                        .await
+                        .with_reason("Failed to send retry RPC message")?;
+                }
             )
         }
     }
@@ -186,3 +289,74 @@ impl fmt::Debug for Node {
         f.write_str(self.id.as_str())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::serve;
+
+    fn request(msg_id: u32) -> Message<Value> {
+        Message {
+            src: "c1".into(),
+            dst: "n0".into(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload: json!({"type": "echo"}),
+            },
+        }
+    }
+
+    /// A node answering each request with how many requests it has handled.
+    fn node() -> (mpsc::Sender<Message<Value>>, mpsc::Receiver<Message<Value>>) {
+        let (in_tx, in_rx) = mpsc::channel(8);
+        let (out_tx, out_rx) = mpsc::channel(8);
+        let init = Init {
+            node_id: "n0".into(),
+            node_ids: vec!["n0".into()],
+        };
+        let calls = Arc::new(AtomicUsize::new(0));
+        let node = Arc::new(Node::from_init(init, out_tx));
+        tokio::spawn(serve(node, in_rx, move |msg, node| async move {
+            let n = calls.fetch_add(1, Ordering::AcqRel) + 1;
+            node.reply(&msg, json!({"type": "echo_ok", "n": n})).await
+        }));
+        (in_tx, out_rx)
+    }
+
+    async fn answer(
+        tx: &mpsc::Sender<Message<Value>>,
+        rx: &mut mpsc::Receiver<Message<Value>>,
+        msg_id: u32,
+    ) -> Value {
+        tx.send(request(msg_id)).await.unwrap();
+        let reply = rx.recv().await.unwrap();
+        assert_eq!(reply.body.in_reply_to, Some(msg_id));
+        reply.body.payload["n"].clone()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicates_get_the_cached_reply() {
+        let (tx, mut rx) = node();
+        assert_eq!(answer(&tx, &mut rx, 1).await, 1);
+        assert_eq!(answer(&tx, &mut rx, 1).await, 1, "handled twice");
+        assert_eq!(answer(&tx, &mut rx, 2).await, 2);
+        assert_eq!(answer(&tx, &mut rx, 1).await, 1, "handled twice");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicates_expire_after_the_window() {
+        let (tx, mut rx) = node();
+        // Out of step with the periodic sweep.
+        tokio::time::sleep(DEDUPE_WINDOW / 2).await;
+        assert_eq!(answer(&tx, &mut rx, 1).await, 1);
+        tokio::time::sleep(DEDUPE_WINDOW - Duration::from_millis(1)).await;
+        assert_eq!(answer(&tx, &mut rx, 1).await, 1, "expired early");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(answer(&tx, &mut rx, 1).await, 2, "outlived the window");
+    }
+}
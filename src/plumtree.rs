@@ -0,0 +1,375 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use compact_str::CompactString;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, instrument};
+
+use crate::{
+    error::{JsonDeError, NodeError},
+    message::Message,
+    node::Node,
+    ranges::{covers, digest},
+};
+
+const PUSH_PERIOD: Duration = Duration::from_millis(200);
+const IHAVE_PERIOD: Duration = Duration::from_millis(1000);
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(1000);
+const DIGEST_PERIOD: Duration = Duration::from_millis(1000);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Gossip {
+    PlumtreeGossip {
+        messages: Vec<(u64, Value)>,
+    },
+    PlumtreeIhave {
+        ids: Vec<u64>,
+    },
+    PlumtreeGraft {
+        ids: Vec<u64>,
+    },
+    PlumtreePrune,
+    /// Every id the sender has, as inclusive ranges.
+    PlumtreeDigest {
+        ranges: Vec<(u64, u64)>,
+    },
+    /// Messages the receiver is missing, and ids the sender is missing in turn.
+    PlumtreeRepair {
+        messages: Vec<(u64, Value)>,
+        want: Vec<u64>,
+    },
+}
+
+impl Gossip {
+    /// Whether `msg` belongs to the protocol and should go to [`Plumtree::handle`].
+    pub fn matches(msg: &Message<Value>) -> bool {
+        msg.body
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .is_some_and(|t| t.starts_with("plumtree_"))
+    }
+}
+
+type Deliver = Box<dyn Fn(u64, &Value) + Send + Sync>;
+
+/// A message id heard of but not received yet. It is grafted from each
+/// announcer in turn until it arrives.
+struct Missing {
+    since: Instant,
+    announcers: Vec<CompactString>,
+}
+
+#[derive(Default)]
+struct State {
+    eager: BTreeSet<CompactString>,
+    lazy: BTreeSet<CompactString>,
+    received: BTreeMap<u64, Value>,
+    /// Messages to push to each eager peer on the next tick.
+    push: BTreeMap<CompactString, Vec<(u64, Value)>>,
+    /// Ids to announce to each lazy peer on the next tick.
+    announce: BTreeMap<CompactString, Vec<u64>>,
+    missing: BTreeMap<u64, Missing>,
+    /// Digests go to one peer after another.
+    digests: usize,
+}
+
+impl State {
+    fn make_eager(&mut self, peer: &CompactString) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.clone());
+    }
+
+    fn make_lazy(&mut self, peer: &CompactString) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.clone());
+    }
+
+    /// Records a message, queueing it for every eager peer and an
+    /// announcement for every lazy one. Returns `false` if it was already
+    /// known.
+    fn accept(&mut self, id: u64, payload: &Value, from: Option<&str>) -> bool {
+        if self.received.contains_key(&id) {
+            return false;
+        }
+        self.received.insert(id, payload.clone());
+        self.missing.remove(&id);
+        for peer in self.eager.iter().filter(|p| Some(p.as_str()) != from) {
+            let push = self.push.entry(peer.clone()).or_default();
+            push.push((id, payload.clone()));
+        }
+        for peer in self.lazy.iter().filter(|p| Some(p.as_str()) != from) {
+            self.announce.entry(peer.clone()).or_default().push(id);
+        }
+        true
+    }
+
+    /// Accepts each message and returns the ones that were new.
+    fn accept_all(&mut self, messages: Vec<(u64, Value)>, from: &str) -> Vec<(u64, Value)> {
+        messages
+            .into_iter()
+            .filter(|(id, payload)| self.accept(*id, payload, Some(from)))
+            .collect()
+    }
+
+    fn found(&self, ids: impl IntoIterator<Item = u64>) -> Vec<(u64, Value)> {
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.received.get(&id)?.clone())))
+            .collect()
+    }
+
+    fn next_digest_peer(&mut self) -> Option<CompactString> {
+        let mut peers = self.eager.union(&self.lazy).collect::<Vec<_>>();
+        if peers.is_empty() {
+            return None;
+        }
+        peers.sort_unstable();
+        self.digests = self.digests.wrapping_add(1);
+        Some(peers[self.digests % peers.len()].clone())
+    }
+}
+
+/// Epidemic broadcast trees: messages are pushed eagerly along a spanning
+/// tree that forms itself out of the peer graph, while the remaining links
+/// only carry periodic IHAVE announcements. A peer that hears of a message it
+/// never received grafts the announcing link into the tree, and duplicates
+/// prune redundant links back out of it.
+///
+/// Pushes and announcements are not retried. Whatever a partition drops is
+/// recovered by periodic digests exchanged with one peer after another.
+///
+/// Messages are identified by a unique `u64` and handed to `deliver` exactly
+/// once per node.
+pub struct Plumtree {
+    node: Arc<Node>,
+    state: Mutex<State>,
+    deliver: Deliver,
+}
+
+impl Plumtree {
+    pub fn new(
+        node: Arc<Node>,
+        deliver: impl Fn(u64, &Value) + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            node,
+            state: Mutex::new(State::default()),
+            deliver: Box::new(deliver),
+        })
+    }
+
+    /// Starts with every peer eager; the tree is carved out by pruning.
+    pub fn set_peers(&self, peers: impl IntoIterator<Item = CompactString>) {
+        let mut state = self.state.lock();
+        state.eager = peers.into_iter().collect();
+        state.lazy.clear();
+    }
+
+    /// Delivers and queues a new message for dissemination, returning `false`
+    /// if it was already known.
+    pub fn broadcast(&self, id: u64, payload: Value) -> bool {
+        let new = self.state.lock().accept(id, &payload, None);
+        if new {
+            (self.deliver)(id, &payload);
+        }
+        new
+    }
+
+    #[instrument("Plumtree", skip_all, fields(src = msg.src.as_str()))]
+    pub async fn handle(&self, msg: &Message<Value>) -> Result<(), NodeError> {
+        let src = &msg.src;
+        match Gossip::de(&msg.body.payload)? {
+            Gossip::PlumtreeGossip { messages } => {
+                let new = {
+                    let mut state = self.state.lock();
+                    let new = state.accept_all(messages, src);
+                    if new.is_empty() {
+                        state.make_lazy(src);
+                    } else {
+                        state.make_eager(src);
+                    }
+                    new
+                };
+                if new.is_empty() {
+                    debug!("Duplicates, pruning");
+                    return self.node.send(src.clone(), Gossip::PlumtreePrune).await;
+                }
+                self.deliver_all(&new);
+                Ok(())
+            }
+            Gossip::PlumtreeIhave { ids } => {
+                let mut state = self.state.lock();
+                for id in ids {
+                    if !state.received.contains_key(&id) {
+                        state
+                            .missing
+                            .entry(id)
+                            .or_insert_with(|| Missing {
+                                since: Instant::now(),
+                                announcers: vec![],
+                            })
+                            .announcers
+                            .push(src.clone());
+                    }
+                }
+                Ok(())
+            }
+            Gossip::PlumtreeGraft { ids } => {
+                let found = {
+                    let mut state = self.state.lock();
+                    state.make_eager(src);
+                    state.found(ids)
+                };
+                if found.is_empty() {
+                    return Ok(());
+                }
+                let res = Gossip::PlumtreeGossip { messages: found };
+                self.node.send(src.clone(), res).await
+            }
+            Gossip::PlumtreePrune => {
+                self.state.lock().make_lazy(src);
+                Ok(())
+            }
+            Gossip::PlumtreeDigest { ranges } => {
+                let (messages, want) = {
+                    let state = self.state.lock();
+                    let messages = state
+                        .received
+                        .iter()
+                        .filter(|(id, _)| !covers(&ranges, **id))
+                        .map(|(id, payload)| (*id, payload.clone()))
+                        .collect::<Vec<_>>();
+                    let want = ranges
+                        .iter()
+                        .flat_map(|&(start, end)| start..=end)
+                        .filter(|id| !state.received.contains_key(id))
+                        .collect::<Vec<_>>();
+                    (messages, want)
+                };
+                if messages.is_empty() && want.is_empty() {
+                    return Ok(());
+                }
+                debug!(sent = messages.len(), wanted = want.len(), "Repairing");
+                let res = Gossip::PlumtreeRepair { messages, want };
+                self.node.send(src.clone(), res).await
+            }
+            // Repairs leave the tree alone: they say nothing about which links
+            // are redundant.
+            Gossip::PlumtreeRepair { messages, want } => {
+                let (new, found) = {
+                    let mut state = self.state.lock();
+                    (state.accept_all(messages, src), state.found(want))
+                };
+                self.deliver_all(&new);
+                if found.is_empty() {
+                    return Ok(());
+                }
+                let res = Gossip::PlumtreeRepair {
+                    messages: found,
+                    want: vec![],
+                };
+                self.node.send(src.clone(), res).await
+            }
+        }
+    }
+
+    /// Flushes pushes to eager peers and announcements to lazy ones, grafts
+    /// links for messages that were announced but did not arrive in time, and
+    /// sends digests. Runs until the node stops.
+    pub async fn run(self: Arc<Self>) -> Result<(), NodeError> {
+        let mut push = tokio::time::interval(PUSH_PERIOD);
+        let mut ihave = tokio::time::interval(IHAVE_PERIOD);
+        let mut digest = tokio::time::interval(DIGEST_PERIOD);
+        for interval in [&mut push, &mut ihave, &mut digest] {
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        }
+        loop {
+            // Ticks that coincide are taken in a fixed order, so a simulation
+            // replays exactly from its seed.
+            tokio::select! {
+                biased;
+                _ = push.tick() => self.flush_pushes().await?,
+                _ = ihave.tick() => self.flush_announcements().await?,
+                _ = digest.tick() => self.send_digest().await?,
+            }
+        }
+    }
+
+    async fn flush_pushes(&self) -> Result<(), NodeError> {
+        let push = std::mem::take(&mut self.state.lock().push);
+        for (peer, messages) in push {
+            self.node
+                .send(peer, Gossip::PlumtreeGossip { messages })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn flush_announcements(&self) -> Result<(), NodeError> {
+        let (announce, grafts) = {
+            let mut state = self.state.lock();
+            let announce = std::mem::take(&mut state.announce);
+            let mut grafts = BTreeMap::<CompactString, Vec<u64>>::new();
+            let now = Instant::now();
+            for (id, missing) in state.missing.iter_mut() {
+                if now - missing.since < GRAFT_TIMEOUT || missing.announcers.is_empty() {
+                    continue;
+                }
+                // Try the next announcer if this one does not answer either.
+                let peer = missing.announcers[0].clone();
+                missing.announcers.rotate_left(1);
+                missing.since = now;
+                grafts.entry(peer).or_default().push(*id);
+            }
+            for peer in grafts.keys() {
+                state.make_eager(peer);
+            }
+            (announce, grafts)
+        };
+        for (peer, ids) in announce {
+            self.node.send(peer, Gossip::PlumtreeIhave { ids }).await?;
+        }
+        for (peer, ids) in grafts {
+            debug!(peer = peer.as_str(), ?ids, "Grafting");
+            self.node.send(peer, Gossip::PlumtreeGraft { ids }).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_digest(&self) -> Result<(), NodeError> {
+        let (peer, ranges) = {
+            let mut state = self.state.lock();
+            let Some(peer) = state.next_digest_peer() else {
+                return Ok(());
+            };
+            (peer, digest(state.received.keys().copied()))
+        };
+        self.node
+            .send(peer, Gossip::PlumtreeDigest { ranges })
+            .await
+    }
+
+    fn deliver_all(&self, messages: &[(u64, Value)]) {
+        for (id, payload) in messages {
+            (self.deliver)(*id, payload);
+        }
+    }
+}
+
+impl fmt::Debug for Plumtree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Plumtree")
+            .field("eager", &state.eager)
+            .field("lazy", &state.lazy)
+            .finish()
+    }
+}
@@ -0,0 +1,42 @@
+use std::ops::Add;
+
+/// Summarizes ids as inclusive ranges of consecutive values, which stays small
+/// when ids are handed out sequentially.
+pub fn digest<T>(ids: impl IntoIterator<Item = T>) -> Vec<(T, T)>
+where
+    T: Copy + Ord + Add<Output = T> + From<u8>,
+{
+    let mut ids = ids.into_iter().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    let mut ranges: Vec<(T, T)> = vec![];
+    for id in ids {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + T::from(1) == id => *end = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges
+}
+
+/// Whether `id` lies in one of the sorted, disjoint `ranges` of a digest.
+pub fn covers<T: Copy + Ord>(ranges: &[(T, T)], id: T) -> bool {
+    let i = ranges.partition_point(|&(start, _)| start <= id);
+    i > 0 && ranges[i - 1].1 >= id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_covers_exactly_its_ids() {
+        let ids = [9u32, 1, 2, 3, 7, 2, 10];
+        let ranges = digest(ids);
+        assert_eq!(ranges, [(1, 3), (7, 7), (9, 10)]);
+        for id in 0..12 {
+            assert_eq!(covers(&ranges, id), ids.contains(&id), "id {id}");
+        }
+        assert!(digest::<u64>([]).is_empty());
+    }
+}
@@ -1,15 +1,72 @@
-use std::fmt::Debug;
+use std::{
+    fmt::{self, Debug},
+    marker::PhantomData,
+    sync::Arc,
+    time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
+use compact_str::format_compact;
+use dashmap::DashMap;
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use tracing::{debug, instrument};
 
 use crate::{
-    error::{JsonDeError, NodeError, RpcError},
+    error::{JsonDeError, JsonSerError, NodeError, RpcError},
     message::Message,
     node::Node,
 };
 
+const MIN_CAS_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_CAS_BACKOFF: Duration = Duration::from_millis(200);
+
+/// The key-value services Maelstrom provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KvService {
+    /// Sequentially consistent.
+    Seq,
+    /// Linearizable.
+    Lin,
+    /// Last write wins, and reads may not reflect any write at all.
+    Lww,
+}
+
+impl KvService {
+    pub const ALL: [KvService; 3] = [KvService::Seq, KvService::Lin, KvService::Lww];
+
+    /// The node id the service answers on.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KvService::Seq => "seq-kv",
+            KvService::Lin => "lin-kv",
+            KvService::Lww => "lww-kv",
+        }
+    }
+
+    /// The service a node id belongs to, if any, for routing replies to
+    /// [`Node::handle_kv`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|svc| svc.name() == name)
+    }
+}
+
+impl fmt::Display for KvService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a compare-and-set ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CasOutcome {
+    Swapped,
+    /// The key held a value other than `from`.
+    Mismatch,
+    /// The key did not exist and was not to be created.
+    Missing,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum KvRequest {
@@ -37,6 +94,22 @@ pub enum KvResponse {
     Error { code: u8, text: String },
 }
 
+/// The node id of Maelstrom's linearizable timestamp oracle.
+pub const TSO: &str = "lin-tso";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TsoRequest {
+    Ts,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TsoResponse {
+    TsOk { ts: u64 },
+    Error { code: u8, text: String },
+}
+
 impl Node {
     pub fn handle_kv(&self, msg: &Message<Value>) -> Result<(), NodeError> {
         match KvResponse::de(&msg.body.payload)? {
@@ -51,32 +124,52 @@ impl Node {
         }
     }
 
+    pub fn handle_tso(&self, msg: &Message<Value>) -> Result<(), NodeError> {
+        match TsoResponse::de(&msg.body.payload)? {
+            TsoResponse::TsOk { ts } => self.ack(msg, Ok(json!(ts))),
+            TsoResponse::Error { code, text } => self.ack(msg, Err(RpcError::Unknown(code, text))),
+        }
+    }
+
+    /// Fetches a timestamp from `lin-tso`, greater than every timestamp it
+    /// handed out before this call began.
+    #[instrument("TSO next", skip(self))]
+    pub async fn tso_next(&self) -> Result<u64, NodeError> {
+        match self.rpc(TSO.into(), TsoRequest::Ts).await? {
+            Ok(ts) => u64::de(&ts),
+            Err(e) => Err(NodeError::new_with(
+                format_compact!("Unexpected response from {TSO}"),
+                e,
+            )),
+        }
+    }
+
     #[instrument("KV read", skip(self))]
     pub async fn kv_read(
         &self,
-        svc: &str,
+        svc: KvService,
         key: impl Into<Value> + Debug,
     ) -> Result<Option<Value>, NodeError> {
         match self
-            .rpc(svc.into(), KvRequest::Read { key: key.into() })
+            .rpc(svc.name().into(), KvRequest::Read { key: key.into() })
             .await?
         {
             Ok(v) => Ok(Some(v)),
             Err(RpcError::KeyNotFound) => Ok(None),
-            Err(e) => Err(NodeError::new_with("Unexpected response from seq-kv", e)),
+            Err(e) => Err(unexpected(svc, e)),
         }
     }
 
     #[instrument("KV write", skip(self))]
     pub async fn kv_write(
         &self,
-        svc: &str,
+        svc: KvService,
         key: impl Into<Value> + Debug,
         val: impl Into<Value> + Debug,
     ) -> Result<(), NodeError> {
         match self
             .rpc(
-                svc.into(),
+                svc.name().into(),
                 KvRequest::Write {
                     key: key.into(),
                     value: val.into(),
@@ -85,36 +178,212 @@ impl Node {
             .await?
         {
             Ok(_) => Ok(()),
-            Err(e) => Err(NodeError::new_with("Unexpected response from seq-kv", e)),
+            Err(e) => Err(unexpected(svc, e)),
         }
     }
 
+    /// Sets `key` to `to` if it holds `from`. With `create_if_not_exists`, a
+    /// missing key is created holding `to` whatever `from` is.
     #[instrument("KV cas", skip(self))]
     pub async fn kv_cas(
         &self,
-        svc: &str,
+        svc: KvService,
         key: impl Into<Value> + Debug,
         from: impl Into<Value> + Debug,
         to: impl Into<Value> + Debug,
-    ) -> Result<bool, NodeError> {
+        create_if_not_exists: bool,
+    ) -> Result<CasOutcome, NodeError> {
         match self
             .rpc(
-                svc.into(),
+                svc.name().into(),
                 KvRequest::Cas {
                     key: key.into(),
                     from: from.into(),
                     to: to.into(),
-                    create_if_not_exists: Some(true),
+                    create_if_not_exists: Some(create_if_not_exists),
                 },
             )
             .await?
         {
-            Ok(_) => Ok(true),
+            Ok(_) => Ok(CasOutcome::Swapped),
             Err(RpcError::CasFailed(msg)) => {
                 debug!(msg, "CAS failed");
-                Ok(false)
+                Ok(CasOutcome::Mismatch)
             }
-            Err(e) => Err(NodeError::new_with("Unexpected response from seq-kv", e)),
+            Err(RpcError::KeyNotFound) => Ok(CasOutcome::Missing),
+            Err(e) => Err(unexpected(svc, e)),
         }
     }
+
+    /// Atomically replaces the value of `key` with `f` of its current value,
+    /// which is `None` if the key does not exist yet, and returns the value
+    /// installed. The value is read and then swapped in with a CAS, and on
+    /// conflict re-read after a randomized, growing backoff and `f` applied
+    /// again. Nothing is written if `f` returns the current value.
+    #[instrument("KV update", skip(self, f))]
+    pub async fn kv_update<T>(
+        &self,
+        svc: KvService,
+        key: impl Into<Value> + Debug,
+        mut f: impl FnMut(Option<&T>) -> T,
+    ) -> Result<T, NodeError>
+    where
+        T: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        let key = key.into();
+        let mut backoff = MIN_CAS_BACKOFF;
+        loop {
+            let old = self.kv_read(svc, key.clone()).await?;
+            let val = old.as_ref().map(T::de).transpose()?;
+            let new = f(val.as_ref());
+            if val.as_ref() == Some(&new) {
+                return Ok(new);
+            }
+            let create = old.is_none();
+            let from = old.unwrap_or(Value::Null);
+            let to = new.ser_val()?;
+            match self.kv_cas(svc, key.clone(), from, to, create).await? {
+                CasOutcome::Swapped => return Ok(new),
+                CasOutcome::Mismatch | CasOutcome::Missing => {}
+            }
+            let jitter = self.rng(|rng| rng.gen_range(0.5..1.5));
+            tokio::time::sleep(backoff.mul_f64(jitter)).await;
+            backoff = (backoff * 2).min(MAX_CAS_BACKOFF);
+        }
+    }
+}
+
+fn unexpected(svc: KvService, e: RpcError) -> NodeError {
+    NodeError::new_with(format_compact!("Unexpected response from {svc}"), e)
+}
+
+/// A client of one KV service for keys of type `K` holding values of type
+/// `V`, both stored as their JSON serialization.
+///
+/// A client made with [`KvClient::cached`] remembers the last value it saw
+/// for each key, and [`KvClient::update`] swaps from that value directly
+/// instead of reading the key first, falling back to a read when the swap
+/// fails. Reads still always go to the service.
+pub struct KvClient<K, V> {
+    node: Arc<Node>,
+    svc: KvService,
+    cache: Option<DashMap<String, V>>,
+    types: PhantomData<fn(&K)>,
+}
+
+impl<K, V> KvClient<K, V>
+where
+    K: Serialize + Debug,
+    V: Serialize + DeserializeOwned + Clone + PartialEq + Debug,
+{
+    pub fn new(node: Arc<Node>, svc: KvService) -> Self {
+        Self {
+            node,
+            svc,
+            cache: None,
+            types: PhantomData,
+        }
+    }
+
+    pub fn cached(node: Arc<Node>, svc: KvService) -> Self {
+        Self {
+            cache: Some(DashMap::new()),
+            ..Self::new(node, svc)
+        }
+    }
+
+    pub fn svc(&self) -> KvService {
+        self.svc
+    }
+
+    pub async fn read(&self, key: &K) -> Result<Option<V>, NodeError> {
+        let key = key.ser_val()?;
+        let val = self
+            .node
+            .kv_read(self.svc, key.clone())
+            .await?
+            .map(V::de)
+            .transpose()?;
+        self.remember(&key, val.as_ref());
+        Ok(val)
+    }
+
+    pub async fn write(&self, key: &K, val: &V) -> Result<(), NodeError> {
+        let key = key.ser_val()?;
+        self.node
+            .kv_write(self.svc, key.clone(), val.ser_val()?)
+            .await?;
+        self.remember(&key, Some(val));
+        Ok(())
+    }
+
+    /// Sets `key` to `to` if it holds `from`, or creates it if `from` is
+    /// `None` and the key does not exist.
+    pub async fn cas(&self, key: &K, from: Option<&V>, to: &V) -> Result<CasOutcome, NodeError> {
+        let key = key.ser_val()?;
+        let create = from.is_none();
+        let from = from.map_or(Ok(Value::Null), |v| v.ser_val())?;
+        let outcome = self
+            .node
+            .kv_cas(self.svc, key.clone(), from, to.ser_val()?, create)
+            .await?;
+        match outcome {
+            CasOutcome::Swapped => self.remember(&key, Some(to)),
+            CasOutcome::Mismatch | CasOutcome::Missing => self.remember(&key, None),
+        }
+        Ok(outcome)
+    }
+
+    /// See [`Node::kv_update`]. A cached client first tries a single swap
+    /// from the value it last saw, if `f` changes it.
+    pub async fn update(
+        &self,
+        key: &K,
+        mut f: impl FnMut(Option<&V>) -> V,
+    ) -> Result<V, NodeError> {
+        let key = key.ser_val()?;
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&key.to_string()).map(|v| v.clone()));
+        if let Some(old) = cached {
+            let new = f(Some(&old));
+            if new != old {
+                let (from, to) = (old.ser_val()?, new.ser_val()?);
+                match self
+                    .node
+                    .kv_cas(self.svc, key.clone(), from, to, false)
+                    .await?
+                {
+                    CasOutcome::Swapped => {
+                        self.remember(&key, Some(&new));
+                        return Ok(new);
+                    }
+                    CasOutcome::Mismatch | CasOutcome::Missing => {
+                        debug!(?key, "Cached value was stale");
+                        self.remember(&key, None);
+                    }
+                }
+            }
+        }
+
+        let new = self.node.kv_update(self.svc, key.clone(), f).await?;
+        self.remember(&key, Some(&new));
+        Ok(new)
+    }
+
+    fn remember(&self, key: &Value, val: Option<&V>) {
+        if let Some(cache) = &self.cache {
+            match val {
+                Some(val) => _ = cache.insert(key.to_string(), val.clone()),
+                None => _ = cache.remove(&key.to_string()),
+            }
+        }
+    }
+}
+
+impl<K, V> Debug for KvClient<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KvClient").field(&self.svc).finish()
+    }
 }
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use futures::Future;
+use miette::bail;
+use serde_json::Value;
+
+use crate::{
+    client::{Concurrency, Opts},
+    error::NodeError,
+    message::Message,
+    node::Node,
+};
+
+use super::{node_ids, run_trial, schedule, Schedule, Trial};
+
+const ENV_PREFIX: &str = "VORTEX_";
+
+/// Runs `trials` seeds in turn and, on the first one that breaks the
+/// workload's invariant, shrinks it to a smaller failing run.
+pub fn search<S, F, FutF>(
+    setup: &S,
+    opts: &Opts,
+    seed: u64,
+    trials: u64,
+    real_time: bool,
+) -> miette::Result<()>
+where
+    S: Fn(Arc<Node>) -> F + Sync,
+    F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
+    FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
+{
+    let nodes = node_ids(opts.node_count);
+    for i in 0..trials {
+        let seed = seed.wrapping_add(i);
+        let schedule = schedule(opts, seed, &nodes);
+        let trial = run_trial(setup, opts, seed, schedule.clone(), real_time)?;
+        if trial.violations.is_empty() {
+            println!("Trial {i} (seed {seed}): ok");
+            continue;
+        }
+
+        println!("Trial {i} (seed {seed}): failed, shrinking...");
+        let mut shrinker = Shrinker {
+            setup,
+            seed,
+            real_time,
+            opts: opts.clone(),
+            schedule,
+            trial,
+        };
+        shrinker.shrink()?;
+
+        println!("Minimal reproduction:\n  {}", shrinker.command_line());
+        for violation in &shrinker.trial.violations {
+            println!("  {violation}");
+        }
+        bail!("Seed {seed} violates the {:?} invariant", opts.workload);
+    }
+
+    println!("{trials} trials passed");
+    Ok(())
+}
+
+struct Shrinker<'a, S> {
+    setup: &'a S,
+    seed: u64,
+    real_time: bool,
+    opts: Opts,
+    schedule: Schedule,
+    trial: Trial,
+}
+
+impl<'a, S, F, FutF> Shrinker<'a, S>
+where
+    S: Fn(Arc<Node>) -> F + Sync,
+    F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
+    FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
+{
+    /// Greedily shortens the run, drops faults and removes clients for as
+    /// long as the smaller run still fails with the same seed.
+    fn shrink(&mut self) -> Result<(), NodeError> {
+        loop {
+            let mut progress = false;
+
+            while self.opts.time_limit > 1 {
+                let mut opts = self.opts.clone();
+                opts.time_limit /= 2;
+                let limit = opts.time_limit * 1000;
+                let mut schedule = self.schedule.clone();
+                schedule.0.retain(|e| e.at < limit);
+                if !self.attempt(opts, schedule)? {
+                    break;
+                }
+                progress = true;
+            }
+
+            let mut i = 0;
+            while i < self.schedule.0.len() {
+                let mut schedule = self.schedule.clone();
+                schedule.0.remove(i);
+                if self.attempt(self.opts.clone(), schedule)? {
+                    progress = true;
+                } else {
+                    i += 1;
+                }
+            }
+
+            let clients = self.opts.concurrency.resolve(self.opts.node_count);
+            if clients > 1 {
+                let mut opts = self.opts.clone();
+                opts.concurrency = Concurrency::Total(clients / 2);
+                progress |= self.attempt(opts, self.schedule.clone())?;
+            }
+
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    fn attempt(&mut self, opts: Opts, schedule: Schedule) -> Result<bool, NodeError> {
+        let trial = run_trial(
+            self.setup,
+            &opts,
+            self.seed,
+            schedule.clone(),
+            self.real_time,
+        )?;
+        if trial.violations.is_empty() {
+            return Ok(false);
+        }
+        self.opts = opts;
+        self.schedule = schedule;
+        self.trial = trial;
+        Ok(true)
+    }
+
+    fn command_line(&self) -> String {
+        let bin = std::env::args().next().unwrap_or_default();
+        let workload = self
+            .opts
+            .workload
+            .to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default();
+        let o = &self.opts;
+        let fresh = if o.fresh_reads { " --fresh-reads" } else { "" };
+        format!(
+            "{}{bin} sim -w {workload} --node-count {} --concurrency {} --rate {} \
+             --time-limit {} --latency {} --timeout {} --recovery {} \
+             --consistency-model {}{fresh} --seed {} --schedule '{}'",
+            env_prefix(std::env::vars()),
+            o.node_count,
+            o.concurrency,
+            o.rate,
+            o.time_limit,
+            o.latency,
+            o.timeout,
+            o.recovery,
+            o.consistency_model,
+            self.seed,
+            self.schedule.to_json(),
+        )
+    }
+}
+
+/// The `VORTEX_*` variables among `vars`, which select node modes such as
+/// the broadcast strategy, as shell assignments to put before a command.
+fn env_prefix(vars: impl Iterator<Item = (String, String)>) -> String {
+    let mut vars = vars
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect::<Vec<_>>();
+    vars.sort();
+    vars.iter()
+        .map(|(name, val)| format!("{name}='{}' ", val.replace('\'', r"'\''")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_prefix_keeps_vortex_vars() {
+        let vars = [
+            ("VORTEX_TOPOLOGY", "tree"),
+            ("RUST_LOG", "debug"),
+            ("VORTEX_BROADCAST", "it's"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert_eq!(
+            env_prefix(vars.into_iter()),
+            r"VORTEX_BROADCAST='it'\''s' VORTEX_TOPOLOGY='tree' "
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use compact_str::CompactString;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+
+use crate::{
+    error::{JsonDeError, JsonSerError},
+    message::{Body, Message},
+    service::{KvRequest, KvResponse, KvService},
+};
+
+use super::Network;
+
+/// A key-value store answering Maelstrom's `read`, `write` and `cas`
+/// requests. `lin-kv` and `lww-kv` are linearizable here, while `seq-kv` is
+/// only sequentially consistent, as in Maelstrom: its reads may return any
+/// state at least as new as the last one the same client saw, picked using
+/// `seed`. Writes and CAS always apply to the latest state.
+pub fn spawn(net: &Network, svc: KvService, seed: u64) {
+    let name = CompactString::from(svc.name());
+    let mut rx = net.register(name.clone());
+    let tx = net.sender();
+    let mut store = Store {
+        stale: (svc == KvService::Seq).then(|| StdRng::seed_from_u64(seed)),
+        ..Store::default()
+    };
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let res = match KvRequest::de(&msg.body.payload) {
+                Ok(req) => store.apply(&msg.src, req),
+                Err(e) => KvResponse::Error {
+                    code: 10,
+                    text: e.reason.to_string(),
+                },
+            };
+            let Ok(payload) = res.ser_val() else { continue };
+            let reply = Message {
+                src: name.clone(),
+                dst: msg.src,
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: msg.body.msg_id,
+                    payload,
+                },
+            };
+            if tx.send(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[derive(Default)]
+struct Store {
+    /// Every value each key has held, with the version that wrote it.
+    keys: HashMap<String, Vec<(u64, Value)>>,
+    version: u64,
+    /// The newest version each client has observed.
+    seen: HashMap<CompactString, u64>,
+    /// Picks which version a read observes, when reads may be stale.
+    stale: Option<StdRng>,
+}
+
+impl Store {
+    fn apply(&mut self, client: &CompactString, req: KvRequest) -> KvResponse {
+        match req {
+            KvRequest::Read { key } => {
+                let at = self.read_version(client);
+                match self.get(&key.to_string(), at) {
+                    Some(value) => KvResponse::ReadOk {
+                        value: value.clone(),
+                    },
+                    None => key_missing(),
+                }
+            }
+            KvRequest::Write { key, value } => {
+                self.put(client, key.to_string(), value);
+                KvResponse::WriteOk
+            }
+            KvRequest::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                let key = key.to_string();
+                self.seen.insert(client.clone(), self.version);
+                match self.get(&key, self.version) {
+                    Some(current) if *current == from => {
+                        self.put(client, key, to);
+                        KvResponse::CasOk
+                    }
+                    Some(current) => KvResponse::Error {
+                        code: 22,
+                        text: format!("expected {from}, but had {current}"),
+                    },
+                    None if create_if_not_exists == Some(true) => {
+                        self.put(client, key, to);
+                        KvResponse::CasOk
+                    }
+                    None => key_missing(),
+                }
+            }
+        }
+    }
+
+    fn read_version(&mut self, client: &CompactString) -> u64 {
+        let seen = self.seen.get(client).copied().unwrap_or(0);
+        let at = match &mut self.stale {
+            Some(rng) => rng.gen_range(seen..=self.version),
+            None => self.version,
+        };
+        self.seen.insert(client.clone(), at);
+        at
+    }
+
+    fn get(&self, key: &str, at: u64) -> Option<&Value> {
+        let versions = self.keys.get(key)?;
+        let i = versions.partition_point(|(v, _)| *v <= at);
+        versions[..i].last().map(|(_, value)| value)
+    }
+
+    fn put(&mut self, client: &CompactString, key: String, value: Value) {
+        self.version += 1;
+        self.keys
+            .entry(key)
+            .or_default()
+            .push((self.version, value));
+        self.seen.insert(client.clone(), self.version);
+    }
+}
+
+fn key_missing() -> KvResponse {
+    KvResponse::Error {
+        code: 20,
+        text: "key does not exist".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn read(store: &mut Store, client: &str, key: &str) -> Option<Value> {
+        match store.apply(&client.into(), KvRequest::Read { key: json!(key) }) {
+            KvResponse::ReadOk { value } => Some(value),
+            _ => None,
+        }
+    }
+
+    fn write(store: &mut Store, client: &str, key: &str, value: u64) {
+        let req = KvRequest::Write {
+            key: json!(key),
+            value: json!(value),
+        };
+        store.apply(&client.into(), req);
+    }
+
+    #[test]
+    fn seq_kv_reads_are_fresh_after_a_barrier() {
+        let mut stale = 0;
+        for seed in 0..20 {
+            let mut store = Store {
+                stale: Some(StdRng::seed_from_u64(seed)),
+                ..Store::default()
+            };
+            write(&mut store, "n1", "x", 1);
+            write(&mut store, "n1", "x", 2);
+            if read(&mut store, "n0", "x") != Some(json!(2)) {
+                stale += 1;
+            }
+
+            write(&mut store, "n0", "barrier:n0", 0);
+            for _ in 0..10 {
+                assert_eq!(read(&mut store, "n0", "x"), Some(json!(2)), "seed {seed}");
+            }
+        }
+        assert!(stale > 0, "no read was stale");
+    }
+}
@@ -0,0 +1,550 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use clap::Parser;
+use compact_str::{format_compact, CompactString};
+use dashmap::DashMap;
+use futures::Future;
+use miette::bail;
+use parking_lot::RwLock;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    process::{Child, Command},
+    sync::mpsc,
+    time::Instant,
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    checker,
+    client::{self, Call, Client, Opts},
+    error::{JsonSerError, NodeError, WithReason},
+    message::{Init, Message},
+    node::Node,
+    serve,
+    service::KvService,
+};
+
+pub use nemesis::Schedule;
+pub use stats::Stats;
+
+use nemesis::Nemesis;
+
+mod harness;
+mod kv;
+pub mod nemesis;
+mod stats;
+mod tso;
+
+const CHANNEL_SIZE: usize = 64;
+
+/// Routes messages between the endpoints of a run, delaying each delivery by
+/// the configured latency.
+#[derive(Clone)]
+pub struct Network {
+    tx: mpsc::Sender<Message<Value>>,
+    routes: Arc<DashMap<CompactString, mpsc::Sender<Message<Value>>>>,
+    /// Partition group of each node; endpoints not listed are never cut off.
+    groups: Arc<RwLock<HashMap<CompactString, usize>>>,
+    msgs: Arc<AtomicU64>,
+    server_msgs: Arc<AtomicU64>,
+}
+
+impl Network {
+    pub fn new(latency: Duration) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Message<Value>>(CHANNEL_SIZE);
+        let routes = Arc::new(DashMap::<_, mpsc::Sender<_>>::new());
+        let groups = Arc::new(RwLock::new(HashMap::new()));
+        let msgs = Arc::new(AtomicU64::new(0));
+        let server_msgs = Arc::new(AtomicU64::new(0));
+
+        let r = routes.clone();
+        let g = groups.clone();
+        let (m, sm) = (msgs.clone(), server_msgs.clone());
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                m.fetch_add(1, Ordering::Relaxed);
+                if is_server(&msg.src) && is_server(&msg.dst) {
+                    sm.fetch_add(1, Ordering::Relaxed);
+                }
+                if severed(&g.read(), &msg.src, &msg.dst) {
+                    debug!(
+                        src = msg.src.as_str(),
+                        dst = msg.dst.as_str(),
+                        "Partitioned"
+                    );
+                    continue;
+                }
+                let Some(route) = r.get(&msg.dst).map(|tx| tx.clone()) else {
+                    warn!(
+                        dst = msg.dst.as_str(),
+                        "Dropping message to unknown endpoint"
+                    );
+                    continue;
+                };
+                tokio::spawn(async move {
+                    tokio::time::sleep(latency).await;
+                    _ = route.send(msg).await;
+                });
+            }
+        });
+
+        Self {
+            tx,
+            routes,
+            groups,
+            msgs,
+            server_msgs,
+        }
+    }
+
+    /// A network with every KV service and the timestamp oracle already on
+    /// it. `seed` picks what seq-kv's stale reads observe.
+    pub fn with_services(latency: Duration, seed: u64) -> Self {
+        let net = Self::new(latency);
+        for svc in KvService::ALL {
+            kv::spawn(&net, svc, seed);
+        }
+        tso::spawn(&net);
+        net
+    }
+
+    pub fn sender(&self) -> mpsc::Sender<Message<Value>> {
+        self.tx.clone()
+    }
+
+    pub fn register(&self, id: impl Into<CompactString>) -> mpsc::Receiver<Message<Value>> {
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        self.routes.insert(id.into(), tx);
+        rx
+    }
+
+    /// Drops messages between nodes in different groups until healed.
+    pub fn partition(&self, groups: &[Vec<CompactString>]) {
+        *self.groups.write() = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.iter().map(move |node| (node.clone(), i)))
+            .collect();
+    }
+
+    pub fn heal(&self) {
+        self.groups.write().clear();
+    }
+
+    /// Messages sent so far, in total and between nodes only. Messages lost
+    /// to a partition still count, as they do in Maelstrom.
+    pub fn msg_counts(&self) -> (u64, u64) {
+        (
+            self.msgs.load(Ordering::Relaxed),
+            self.server_msgs.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Nodes are named `n0`, `n1`, ... while clients and services are not.
+fn is_server(id: &str) -> bool {
+    id.starts_with('n')
+}
+
+fn severed(groups: &HashMap<CompactString, usize>, src: &str, dst: &str) -> bool {
+    matches!((groups.get(src), groups.get(dst)), (Some(a), Some(b)) if a != b)
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "sim", about = "Run the workload against an in-process cluster")]
+struct SimArgs {
+    /// Wait for timers in wall-clock time instead of advancing a virtual clock.
+    #[arg(long)]
+    real_time: bool,
+    /// Run this many trials with consecutive seeds, shrinking the first failure.
+    #[arg(long)]
+    trials: Option<u64>,
+    /// Inject exactly these faults instead of a random `--nemesis` schedule.
+    #[arg(long)]
+    schedule: Option<Schedule>,
+    #[command(flatten)]
+    opts: Opts,
+}
+
+/// The outcome of running a workload once.
+pub struct Trial {
+    pub history: Vec<Call>,
+    pub violations: Vec<String>,
+    pub elapsed: Duration,
+    pub stats: Stats,
+}
+
+impl Trial {
+    fn new(opts: &Opts, net: &Network, history: Vec<Call>, elapsed: Duration) -> Self {
+        let violations = checker::check(opts, &history);
+        let (msgs, server_msgs) = net.msg_counts();
+        Self {
+            stats: Stats::new(msgs, server_msgs, &history),
+            history,
+            violations,
+            elapsed,
+        }
+    }
+}
+
+/// Runs `opts.workload` against `opts.node_count` in-process nodes, each
+/// created with `setup` as in [`crate::main_loop`].
+///
+/// Unless `--real-time` is given the runtime's clock is paused, so it jumps
+/// straight to the next pending timer whenever every task is idle. Timers
+/// still fire in deadline order, which keeps runs faithful to wall-clock ones
+/// while a 20 second workload finishes in well under a second.
+pub async fn run<S, F, FutF>(setup: S) -> miette::Result<()>
+where
+    S: Fn(Arc<Node>) -> F + Sync,
+    F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
+    FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
+{
+    let args = SimArgs::parse_from(std::env::args().skip(1));
+    let seed = args.opts.seed.unwrap_or_else(rand::random);
+    if let Some(trials) = args.trials {
+        return harness::search(&setup, &args.opts, seed, trials, args.real_time);
+    }
+
+    let schedule = args
+        .schedule
+        .unwrap_or_else(|| schedule(&args.opts, seed, &node_ids(args.opts.node_count)));
+    println!("Seed: {seed}");
+    let wall = std::time::Instant::now();
+    let trial = run_trial(&setup, &args.opts, seed, schedule, args.real_time)?;
+    println!("Simulated {:.1?} in {:.1?}", trial.elapsed, wall.elapsed());
+    report(&args.opts, &trial)
+}
+
+/// Runs one trial on a fresh runtime, so nothing a node spawned outlives it.
+pub fn run_trial<S, F, FutF>(
+    setup: &S,
+    opts: &Opts,
+    seed: u64,
+    schedule: Schedule,
+    real_time: bool,
+) -> Result<Trial, NodeError>
+where
+    S: Fn(Arc<Node>) -> F + Sync,
+    F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
+    FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .start_paused(!real_time)
+                    .build()
+                    .with_reason("Failed to build simulation runtime")?
+                    .block_on(in_process(setup, opts, seed, schedule))
+            })
+            .join()
+            .map_err(|_| NodeError::new("Simulation panicked"))?
+    })
+}
+
+async fn in_process<S, F, FutF>(
+    setup: &S,
+    opts: &Opts,
+    seed: u64,
+    schedule: Schedule,
+) -> Result<Trial, NodeError>
+where
+    S: Fn(Arc<Node>) -> F,
+    F: FnOnce(Message<Value>, Arc<Node>) -> FutF + Send + Sync + Clone + 'static,
+    FutF: Future<Output = Result<(), NodeError>> + Send + Sync,
+{
+    let start = Instant::now();
+    let net = network(opts, seed);
+    let nodes = node_ids(opts.node_count);
+
+    let (err_tx, mut err_rx) = mpsc::channel(1);
+    let mut seeds = StdRng::seed_from_u64(seed);
+    for id in &nodes {
+        let rx = net.register(id.clone());
+        let node = Node::from_init(
+            Init {
+                node_id: id.clone(),
+                node_ids: nodes.clone(),
+            },
+            net.sender(),
+        );
+        let node = Arc::new(node.with_seed(seeds.gen()));
+        let func = setup(node.clone());
+        let err_tx = err_tx.clone();
+        let id = id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(node, rx, func).await {
+                _ = err_tx.send((id, e)).await;
+            }
+        });
+    }
+
+    tokio::select! {
+        history = drive(opts, &net, &nodes, seed, schedule) => {
+            Ok(Trial::new(opts, &net, history?, start.elapsed()))
+        }
+        Some((id, err)) = err_rx.recv() => Ok(Trial {
+            history: vec![],
+            violations: vec![format!("{id} crashed: {}", err.reason)],
+            elapsed: start.elapsed(),
+            stats: Stats::default(),
+        }),
+    }
+}
+
+/// Runs `opts.workload` against `opts.node_count` copies of `bin`, talking to
+/// each over its stdin and stdout like Maelstrom does.
+pub async fn run_processes(opts: &Opts, bin: &Path) -> miette::Result<()> {
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    let net = network(opts, seed);
+    let nodes = node_ids(opts.node_count);
+
+    let mut children = vec![];
+    for id in &nodes {
+        children.push(spawn_process(&net, bin, id.clone())?);
+    }
+
+    let trial = run_external(opts, &net, &nodes, seed).await?;
+    for mut child in children {
+        _ = child.kill().await;
+    }
+    report(opts, &trial)
+}
+
+/// Runs `opts.workload` against nodes already listening on `addrs`, which
+/// become `n0`, `n1`, ... in order. A node binary listens on the address in
+/// `VORTEX_LISTEN` instead of using stdin and stdout.
+pub async fn run_tcp(opts: &Opts, addrs: &[String]) -> miette::Result<()> {
+    let opts = Opts {
+        node_count: addrs.len(),
+        ..opts.clone()
+    };
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    let net = network(&opts, seed);
+    let nodes = node_ids(opts.node_count);
+
+    for (id, addr) in nodes.iter().zip(addrs) {
+        let conn = TcpStream::connect(addr)
+            .await
+            .with_reason(format!("Failed to connect to {addr}"))?;
+        conn.set_nodelay(true)
+            .with_reason(format!("Failed to configure connection to {addr}"))?;
+        let (reader, writer) = conn.into_split();
+        bridge(&net, id.clone(), reader, writer);
+    }
+
+    let trial = run_external(&opts, &net, &nodes, seed).await?;
+    report(&opts, &trial)
+}
+
+/// Initializes nodes running outside this process and drives the workload
+/// against them.
+async fn run_external(
+    opts: &Opts,
+    net: &Network,
+    nodes: &[CompactString],
+    seed: u64,
+) -> Result<Trial, NodeError> {
+    let mut init = Client::new(net, "c0");
+    for id in nodes {
+        let req = Init {
+            node_id: id.clone(),
+            node_ids: nodes.to_vec(),
+        }
+        .ser_val()?;
+        init.call(id, req, Duration::from_secs(opts.timeout))
+            .await?
+            .with_reason(format_compact!("{id} did not answer init"))?;
+    }
+
+    println!("Seed: {seed}");
+    let start = Instant::now();
+    let history = drive(opts, net, nodes, seed, schedule(opts, seed, nodes)).await?;
+    Ok(Trial::new(opts, net, history, start.elapsed()))
+}
+
+fn network(opts: &Opts, seed: u64) -> Network {
+    Network::with_services(Duration::from_millis(opts.latency), seed)
+}
+
+fn schedule(opts: &Opts, seed: u64, nodes: &[CompactString]) -> Schedule {
+    if opts.nemesis.contains(&Nemesis::Partition) {
+        Schedule::random(
+            seed,
+            nodes,
+            Duration::from_secs_f64(opts.nemesis_interval),
+            Duration::from_secs(opts.time_limit),
+        )
+    } else {
+        Schedule::default()
+    }
+}
+
+async fn drive(
+    opts: &Opts,
+    net: &Network,
+    nodes: &[CompactString],
+    seed: u64,
+    schedule: Schedule,
+) -> Result<Vec<Call>, NodeError> {
+    schedule.spawn(
+        net.clone(),
+        Instant::now(),
+        Duration::from_secs(opts.time_limit),
+    );
+    client::run(opts, net, nodes, seed).await
+}
+
+fn report(opts: &Opts, trial: &Trial) -> miette::Result<()> {
+    client::summarize(&trial.history);
+    println!("{}", trial.stats);
+    if let Some(path) = &opts.history {
+        client::write_history(path, &trial.history)?;
+        println!("History written to {}", path.display());
+    }
+    if trial.violations.is_empty() {
+        println!("Everything looks good");
+        return Ok(());
+    }
+    for violation in &trial.violations {
+        println!("  {violation}");
+    }
+    bail!("{} invariant violations", trial.violations.len())
+}
+
+fn node_ids(count: usize) -> Vec<CompactString> {
+    (0..count).map(|i| format_compact!("n{i}")).collect()
+}
+
+fn spawn_process(net: &Network, bin: &Path, id: CompactString) -> Result<Child, NodeError> {
+    let mut child = Command::new(bin)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_reason(format!("Failed to spawn {}", bin.display()))?;
+    let stdin = child.stdin.take().with_reason("Child has no stdin")?;
+    let stdout = child.stdout.take().with_reason("Child has no stdout")?;
+
+    bridge(net, id, stdout, stdin);
+    Ok(child)
+}
+
+/// Connects node `id` to the network through a stream of JSON lines.
+fn bridge<R, W>(net: &Network, id: CompactString, reader: R, mut writer: W)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut rx = net.register(id.clone());
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let Ok(mut line) = msg.ser_str() else {
+                continue;
+            };
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let tx = net.sender();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match serde_json::from_str(&line) {
+                Ok(msg) => {
+                    if tx.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!(node = id.as_str(), line, ?e, "Failed to parse node output"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::error::JsonDeError;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Add { delta: u64 },
+        AddOk,
+        Read,
+        ReadOk { value: u64 },
+    }
+
+    /// A g-counter on one contended lin-kv key, whose CAS retries back off by
+    /// a random jitter.
+    async fn counter(msg: Message<Value>, node: Arc<Node>) -> Result<(), NodeError> {
+        if KvService::from_name(&msg.src).is_some() {
+            return node.handle_kv(&msg);
+        }
+        let reply = match Request::de(&msg.body.payload)? {
+            Request::Add { delta } => {
+                node.kv_update(KvService::Lin, "counter", |v: Option<&u64>| {
+                    v.copied().unwrap_or(0) + delta
+                })
+                .await?;
+                Request::AddOk
+            }
+            Request::Read => {
+                let value = node.kv_read(KvService::Lin, "counter").await?;
+                Request::ReadOk {
+                    value: value.map_or(Ok(0), |v| u64::de(&v))?,
+                }
+            }
+            _ => return Ok(()),
+        };
+        node.reply(&msg, reply).await
+    }
+
+    #[test]
+    fn trials_replay_from_their_seed() {
+        let SimArgs { opts, .. } = SimArgs::parse_from([
+            "sim",
+            "-w",
+            "g-counter",
+            "--node-count",
+            "3",
+            "--concurrency",
+            "5n",
+            "--rate",
+            "300",
+            "--time-limit",
+            "3",
+            "--latency",
+            "10",
+        ]);
+        let setup = |_| counter;
+        let trial = || {
+            let schedule = schedule(&opts, 3, &node_ids(opts.node_count));
+            run_trial(&setup, &opts, 3, schedule, false).unwrap()
+        };
+        let (a, b) = (trial(), trial());
+        assert!(a.violations.is_empty(), "{:?}", a.violations);
+        assert_eq!(a.stats.msgs, b.stats.msgs);
+        assert_eq!(a.history.len(), b.history.len());
+    }
+}
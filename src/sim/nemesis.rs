@@ -0,0 +1,117 @@
+use std::{str::FromStr, time::Duration};
+
+use clap::ValueEnum;
+use compact_str::CompactString;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::info;
+
+use super::Network;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nemesis {
+    Partition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum Fault {
+    Partition { groups: Vec<Vec<CompactString>> },
+    Heal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Milliseconds since the start of the run.
+    pub at: u64,
+    #[serde(flatten)]
+    pub fault: Fault,
+}
+
+/// A list of faults to inject, given on the command line as a JSON array.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule(pub Vec<Event>);
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map_err(|e| format!("Invalid schedule: {e}"))
+    }
+}
+
+impl Schedule {
+    /// Alternates random partitions and heals roughly every `interval`.
+    pub fn random(
+        seed: u64,
+        nodes: &[CompactString],
+        interval: Duration,
+        time_limit: Duration,
+    ) -> Self {
+        let mut events = vec![];
+        if nodes.len() < 2 || interval.is_zero() {
+            return Self(events);
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut at = Duration::ZERO;
+        loop {
+            at += interval.mul_f64(rng.gen_range(0.5..1.5));
+            if at >= time_limit {
+                break;
+            }
+            let fault = match events.last() {
+                Some(Event {
+                    fault: Fault::Partition { .. },
+                    ..
+                }) => Fault::Heal,
+                _ => Fault::Partition {
+                    groups: partition(&mut rng, nodes),
+                },
+            };
+            events.push(Event {
+                at: at.as_millis() as u64,
+                fault,
+            });
+        }
+        Self(events)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Injects the faults in order, healing the network at `heal_at` so the
+    /// final reads see a connected cluster.
+    pub fn spawn(self, net: Network, start: Instant, heal_at: Duration) {
+        tokio::spawn(async move {
+            for event in self.0 {
+                let at = Duration::from_millis(event.at);
+                if at >= heal_at {
+                    break;
+                }
+                tokio::time::sleep_until(start + at).await;
+                info!(?event, "Nemesis");
+                match event.fault {
+                    Fault::Partition { groups } => net.partition(&groups),
+                    Fault::Heal => net.heal(),
+                }
+            }
+            tokio::time::sleep_until(start + heal_at).await;
+            net.heal();
+        });
+    }
+}
+
+fn partition(rng: &mut StdRng, nodes: &[CompactString]) -> Vec<Vec<CompactString>> {
+    let mut nodes = nodes.to_vec();
+    nodes.shuffle(rng);
+    let split = if rng.gen_bool(0.5) {
+        1
+    } else {
+        rng.gen_range(1..nodes.len())
+    };
+    let rest = nodes.split_off(split);
+    vec![nodes, rest]
+}
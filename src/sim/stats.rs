@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    checker::broadcast::read_messages,
+    client::{Call, Outcome},
+};
+
+/// Network and latency figures for one run, as Maelstrom reports them.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub msgs: u64,
+    /// Messages sent from one node to another, excluding clients and services.
+    pub server_msgs: u64,
+    /// Successful client operations, not counting the final reads.
+    pub ops: u64,
+    pub latency: Percentiles,
+    /// Time from a broadcast being sent until every later read includes it.
+    pub stable_latency: Option<Percentiles>,
+}
+
+/// Latencies in milliseconds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub fn new(msgs: u64, server_msgs: u64, history: &[Call]) -> Self {
+        let ops = history
+            .iter()
+            .filter(|c| !c.is_final && c.outcome() == Outcome::Ok)
+            .collect::<Vec<_>>();
+        let stable = stable_latencies(history);
+        Self {
+            msgs,
+            server_msgs,
+            ops: ops.len() as u64,
+            latency: Percentiles::new(ops.iter().map(|c| c.end - c.start).collect()),
+            stable_latency: (!stable.is_empty()).then(|| Percentiles::new(stable)),
+        }
+    }
+
+    pub fn msgs_per_op(&self) -> f64 {
+        self.server_msgs as f64 / self.ops.max(1) as f64
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} messages, {} between servers, {:.2} msgs-per-op over {} ops",
+            self.msgs,
+            self.server_msgs,
+            self.msgs_per_op(),
+            self.ops
+        )?;
+        write!(f, "Latency: {}", self.latency)?;
+        if let Some(stable) = &self.stable_latency {
+            write!(f, "\nStable latency: {stable}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Percentiles {
+    /// Takes latencies in microseconds.
+    fn new(mut micros: Vec<u64>) -> Self {
+        micros.sort_unstable();
+        let at = |q: f64| {
+            let i = ((micros.len() as f64 * q).ceil() as usize).saturating_sub(1);
+            micros.get(i).map_or(0.0, |&us| us as f64 / 1000.0)
+        };
+        Self {
+            p50: at(0.5),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:.0}ms, p99 {:.0}ms, max {:.0}ms",
+            self.p50, self.p99, self.max
+        )
+    }
+}
+
+/// A broadcast value becomes stable once the last read that missed it has
+/// finished. Values no read sees after that are lost rather than slow, and
+/// are left to the checker.
+fn stable_latencies(history: &[Call]) -> Vec<u64> {
+    let sent = history
+        .iter()
+        .filter(|c| c.req_type() == "broadcast" && c.ok().is_some())
+        .filter_map(|c| Some((c.req.get("message")?.as_u64()?, c.start)))
+        .collect::<BTreeMap<_, _>>();
+    if sent.is_empty() {
+        return vec![];
+    }
+
+    let reads = history
+        .iter()
+        .filter(|c| c.req_type() == "read")
+        .filter_map(|c| Some((c, read_messages(c.ok()?))))
+        .collect::<Vec<_>>();
+    sent.iter()
+        .filter_map(|(message, &start)| {
+            let stable = reads
+                .iter()
+                .filter(|(c, messages)| c.start >= start && !messages.contains(message))
+                .map(|(c, _)| c.end)
+                .fold(start, u64::max);
+            reads
+                .iter()
+                .any(|(c, messages)| c.start >= stable && messages.contains(message))
+                .then(|| stable - start)
+        })
+        .collect()
+}
@@ -0,0 +1,46 @@
+use compact_str::CompactString;
+
+use crate::{
+    error::{JsonDeError, JsonSerError},
+    message::{Body, Message},
+    service::{TsoRequest, TsoResponse, TSO},
+};
+
+use super::Network;
+
+/// A timestamp oracle answering Maelstrom's `ts` requests with a counter
+/// starting at 1. It is a single task, so timestamps are linearizable.
+pub fn spawn(net: &Network) {
+    let name = CompactString::from(TSO);
+    let mut rx = net.register(name.clone());
+    let tx = net.sender();
+    let mut next = 1;
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let res = match TsoRequest::de(&msg.body.payload) {
+                Ok(TsoRequest::Ts) => {
+                    next += 1;
+                    TsoResponse::TsOk { ts: next - 1 }
+                }
+                Err(e) => TsoResponse::Error {
+                    code: 10,
+                    text: e.reason.to_string(),
+                },
+            };
+            let Ok(payload) = res.ser_val() else { continue };
+            let reply = Message {
+                src: name.clone(),
+                dst: msg.src,
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: msg.body.msg_id,
+                    payload,
+                },
+            };
+            if tx.send(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use compact_str::{format_compact, CompactString};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::error::NodeError;
+
+const TOPOLOGY_ENV: &str = "VORTEX_TOPOLOGY";
+const RANDOM_SEED: u64 = 0x5eed;
+
+/// How a node picks the peers it gossips with. Every strategy other than
+/// `Given` is computed from `node_ids` alone, so all nodes agree on the same
+/// undirected graph without coordinating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Topology {
+    /// Whatever the `topology` message says.
+    #[default]
+    Given,
+    /// A breadth-first spanning tree of the given topology, rooted at the
+    /// first node.
+    SpanningTree,
+    /// Node `i` is the parent of nodes `k * i + 1 ..= k * i + k`.
+    Tree(usize),
+    /// The first node is connected to every other node.
+    Star,
+    /// The union of `k / 2` random Hamiltonian cycles, a k-regular expander
+    /// with high probability.
+    Random(usize),
+    /// A ring where node `i` is also linked to `i + 2^j` for every `j`.
+    Chord,
+}
+
+impl Topology {
+    /// Reads the strategy from `VORTEX_TOPOLOGY`, defaulting to `given`.
+    pub fn from_env() -> Result<Self, NodeError> {
+        match std::env::var(TOPOLOGY_ENV) {
+            Ok(s) => s.parse().map_err(NodeError::new),
+            Err(_) => Ok(Topology::Given),
+        }
+    }
+
+    pub fn peers(
+        &self,
+        id: &str,
+        node_ids: &[CompactString],
+        given: &HashMap<CompactString, Vec<CompactString>>,
+    ) -> Vec<CompactString> {
+        let edges = match self {
+            Topology::Given => return given.get(id).cloned().unwrap_or_default(),
+            Topology::SpanningTree => spanning_tree(node_ids, given),
+            Topology::Tree(k) => tree(node_ids.len(), *k),
+            Topology::Star => (1..node_ids.len()).map(|i| (0, i)).collect(),
+            Topology::Random(k) => random(node_ids.len(), *k),
+            Topology::Chord => chord(node_ids.len()),
+        };
+        let Some(me) = node_ids.iter().position(|n| *n == id) else {
+            return vec![];
+        };
+        edges
+            .into_iter()
+            .filter_map(|(a, b)| match (a == me, b == me) {
+                (true, false) => Some(b),
+                (false, true) => Some(a),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|i| node_ids[i].clone())
+            .collect()
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Topology::Given => write!(f, "given"),
+            Topology::SpanningTree => write!(f, "spanning-tree"),
+            Topology::Tree(k) => write!(f, "tree{k}"),
+            Topology::Star => write!(f, "star"),
+            Topology::Random(k) => write!(f, "random{k}"),
+            Topology::Chord => write!(f, "chord"),
+        }
+    }
+}
+
+/// Accepts `given`, `spanning-tree`, `tree<k>`, `star`, `random<k>` and
+/// `chord`, e.g. `tree4` or `random6`.
+impl FromStr for Topology {
+    type Err = CompactString;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let arity = |n: &str| match n.parse() {
+            Ok(k) if k > 0 => Ok(k),
+            _ => Err(format_compact!("Invalid topology: {s}")),
+        };
+        match s {
+            "given" => Ok(Topology::Given),
+            "spanning-tree" => Ok(Topology::SpanningTree),
+            "star" => Ok(Topology::Star),
+            "chord" => Ok(Topology::Chord),
+            _ => match (s.strip_prefix("tree"), s.strip_prefix("random")) {
+                (Some(k), _) => arity(k).map(Topology::Tree),
+                (_, Some(k)) => arity(k).map(Topology::Random),
+                _ => Err(format_compact!("Unknown topology: {s}")),
+            },
+        }
+    }
+}
+
+fn spanning_tree(
+    node_ids: &[CompactString],
+    given: &HashMap<CompactString, Vec<CompactString>>,
+) -> Vec<(usize, usize)> {
+    let index = node_ids
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (n, i))
+        .collect::<HashMap<_, _>>();
+    let mut seen = vec![false; node_ids.len()];
+    let mut queue = VecDeque::new();
+    let mut edges = vec![];
+    for root in 0..node_ids.len() {
+        // Start a new tree for every part the given topology leaves disconnected.
+        if seen[root] {
+            continue;
+        }
+        if root > 0 {
+            edges.push((0, root));
+        }
+        seen[root] = true;
+        queue.push_back(root);
+        while let Some(i) = queue.pop_front() {
+            let neighbours = given.get(&node_ids[i]).into_iter().flatten();
+            for j in neighbours.filter_map(|n| index.get(n).copied()) {
+                if !seen[j] {
+                    seen[j] = true;
+                    edges.push((i, j));
+                    queue.push_back(j);
+                }
+            }
+        }
+    }
+    edges
+}
+
+fn tree(n: usize, k: usize) -> Vec<(usize, usize)> {
+    (1..n).map(|i| ((i - 1) / k, i)).collect()
+}
+
+fn random(n: usize, k: usize) -> Vec<(usize, usize)> {
+    let mut rng = StdRng::seed_from_u64(RANDOM_SEED);
+    let mut order = (0..n).collect::<Vec<_>>();
+    let mut edges = vec![];
+    for _ in 0..(k / 2).max(1) {
+        order.shuffle(&mut rng);
+        edges.extend((0..n).map(|i| (order[i], order[(i + 1) % n])));
+    }
+    edges
+}
+
+fn chord(n: usize) -> Vec<(usize, usize)> {
+    let mut edges = vec![];
+    for i in 0..n {
+        let mut step = 1;
+        while step < n {
+            edges.push((i, (i + step) % n));
+            step *= 2;
+        }
+    }
+    edges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [usize; 6] = [1, 2, 3, 5, 25, 64];
+
+    fn ids(n: usize) -> Vec<CompactString> {
+        (0..n).map(|i| format_compact!("n{i}")).collect()
+    }
+
+    /// Each node's peers by index, checking that every link goes both ways.
+    fn graph(
+        topology: Topology,
+        n: usize,
+        given: &HashMap<CompactString, Vec<CompactString>>,
+    ) -> Vec<Vec<usize>> {
+        let ids = ids(n);
+        let graph = ids
+            .iter()
+            .map(|id| {
+                topology
+                    .peers(id, &ids, given)
+                    .iter()
+                    .map(|p| ids.iter().position(|i| i == p).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        for (a, peers) in graph.iter().enumerate() {
+            assert!(
+                !peers.contains(&a),
+                "{topology} n={n}: n{a} links to itself"
+            );
+            for &b in peers {
+                assert!(
+                    graph[b].contains(&a),
+                    "{topology} n={n}: n{a}-n{b} is one-way"
+                );
+            }
+        }
+        graph
+    }
+
+    /// The longest shortest path, asserting that every node is reachable.
+    fn diameter(graph: &[Vec<usize>]) -> usize {
+        (0..graph.len())
+            .map(|start| {
+                let mut dist = vec![None; graph.len()];
+                dist[start] = Some(0);
+                let mut queue = VecDeque::from([start]);
+                while let Some(a) = queue.pop_front() {
+                    for &b in &graph[a] {
+                        if dist[b].is_none() {
+                            dist[b] = Some(dist[a].unwrap() + 1);
+                            queue.push_back(b);
+                        }
+                    }
+                }
+                dist.iter().map(|d| d.expect("disconnected")).max().unwrap()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn edges(graph: &[Vec<usize>]) -> usize {
+        graph.iter().map(Vec::len).sum::<usize>() / 2
+    }
+
+    fn max_degree(graph: &[Vec<usize>]) -> usize {
+        graph.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    /// Rows of a grid, linked left, right, up and down, like Maelstrom's.
+    fn grid(n: usize, width: usize) -> HashMap<CompactString, Vec<CompactString>> {
+        let ids = ids(n);
+        (0..n)
+            .map(|i| {
+                let peers = [
+                    (i % width > 0).then(|| i - 1),
+                    (i % width + 1 < width && i + 1 < n).then(|| i + 1),
+                    i.checked_sub(width),
+                    Some(i + width).filter(|j| *j < n),
+                ];
+                let peers = peers.into_iter().flatten().map(|j| ids[j].clone());
+                (ids[i].clone(), peers.collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trees_are_spanning_trees_of_bounded_degree() {
+        for n in SIZES {
+            for k in [1, 2, 4] {
+                let g = graph(Topology::Tree(k), n, &HashMap::new());
+                diameter(&g);
+                assert_eq!(edges(&g), n.saturating_sub(1), "tree{k} n={n}");
+                assert!(max_degree(&g) <= k + 1, "tree{k} n={n}");
+            }
+            let g = graph(Topology::Tree(2), n, &HashMap::new());
+            // Twice the depth of a complete binary tree.
+            assert!(diameter(&g) <= 2 * n.ilog2() as usize, "tree2 n={n}");
+        }
+    }
+
+    #[test]
+    fn star_links_everyone_to_the_first_node() {
+        for n in SIZES {
+            let g = graph(Topology::Star, n, &HashMap::new());
+            assert_eq!(diameter(&g), n.saturating_sub(1).min(2), "n={n}");
+            assert_eq!(g[0].len(), n - 1);
+            assert!(g[1..].iter().all(|peers| peers == &[0]));
+        }
+    }
+
+    #[test]
+    fn random_graphs_are_connected_with_degree_at_most_k() {
+        for n in SIZES {
+            for k in [2, 3, 4, 6] {
+                let g = graph(Topology::Random(k), n, &HashMap::new());
+                diameter(&g);
+                assert!(max_degree(&g) <= k.max(2), "random{k} n={n}");
+                if n >= 3 {
+                    assert!(g.iter().all(|peers| peers.len() >= 2), "random{k} n={n}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn chord_reaches_everyone_in_log_hops() {
+        for n in SIZES {
+            let g = graph(Topology::Chord, n, &HashMap::new());
+            let hops = n.next_power_of_two().ilog2() as usize;
+            assert!(diameter(&g) <= hops, "n={n}");
+            assert!(max_degree(&g) <= 2 * hops, "n={n}");
+        }
+    }
+
+    #[test]
+    fn spanning_tree_keeps_given_links_and_joins_parts() {
+        for n in SIZES {
+            let given = grid(n, 5);
+            let g = graph(Topology::SpanningTree, n, &given);
+            diameter(&g);
+            assert_eq!(edges(&g), n.saturating_sub(1), "n={n}");
+            for (a, peers) in g.iter().enumerate() {
+                let linked = &given[&format_compact!("n{a}")];
+                assert!(peers
+                    .iter()
+                    .all(|b| linked.contains(&format_compact!("n{b}"))));
+            }
+        }
+        // Parts the given topology leaves apart are joined through the first node.
+        let mut given = grid(10, 5);
+        for row in [0, 5] {
+            given.insert(format_compact!("n{}", row + 4), vec![]);
+        }
+        for i in 0..10 {
+            given
+                .get_mut(&format_compact!("n{i}"))
+                .unwrap()
+                .retain(|p| {
+                    let j = p[1..].parse::<usize>().unwrap();
+                    (j < 5) == (i < 5) && j % 5 != 4
+                });
+        }
+        let g = graph(Topology::SpanningTree, 10, &given);
+        diameter(&g);
+        assert_eq!(edges(&g), 9);
+    }
+
+    #[test]
+    fn given_is_used_as_is() {
+        let given = grid(25, 5);
+        let g = graph(Topology::Given, 25, &given);
+        assert_eq!(diameter(&g), 8);
+        assert_eq!(max_degree(&g), 4);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for t in [
+            Topology::Given,
+            Topology::SpanningTree,
+            Topology::Tree(3),
+            Topology::Star,
+            Topology::Random(6),
+            Topology::Chord,
+        ] {
+            assert_eq!(t.to_string().parse(), Ok(t));
+        }
+        for bad in ["tree0", "random", "ring"] {
+            assert!(bad.parse::<Topology>().is_err(), "{bad}");
+        }
+    }
+}